@@ -1,182 +1,1070 @@
 use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashSet, VecDeque},
+    ffi::OsStr,
+    fmt::Write as _,
     fs,
-    io::{BufRead, BufReader, Write},
-    path::PathBuf,
-    sync::mpsc::{self, Receiver},
+    hash::{DefaultHasher, Hash, Hasher},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::Command,
+    rc::Rc,
+    sync::OnceLock,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use chrono::Local;
+use chrono::{Local, Utc};
 use eframe::egui::{
     self, Key, Response, Ui, global_theme_preference_switch, scroll_area::ScrollBarVisibility,
 };
 use egui::Margin;
-use egui::text::CCursor;
-use portable_pty::{CommandBuilder, PtySize, native_pty_system};
+use egui::text::{CCursor, CCursorRange};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     WINDOW_TITLE,
-    completions_and_hints::{SapfDictionary, get_current_word_for_completion, get_word_at_cursor},
-    ui::setup_custom_style,
-    window::custom_window_frame,
+    batch::{FileBatch, file_name},
+    completions_and_hints::{
+        CompletionItem, CompletionKind, SapfDictionary, completion_range, defined_words,
+        expand_snippet, find_definitions, get_current_word_for_completion, get_word_at_cursor,
+        usage_snippet,
+    },
+    console::{ConsoleLine, LineKind, error_subject},
+    diff::{DiffKind, DiffLine, diff_lines},
+    formatter::run_formatter,
+    help::help_ui,
+    keymap::Keymap,
+    log,
+    minimap::{LineOverview, line_overview, minimap_ui},
+    patterns::{Pattern, get_patterns_file_path, load_patterns},
+    regions::{Region, find_regions},
+    sapf::SapfConnection,
+    search::find_in_content,
+    settings::{
+        ConsoleLayout, DEFAULT_CONSOLE_HEIGHT, MAX_FONT_SIZE, MIN_FONT_SIZE, Settings,
+        get_config_dir,
+    },
+    text::{
+        DecodedText, LineEnding, LineIndex, char_to_byte, clean_eval_code, decode_text,
+        normalize_loaded_text, normalize_whitespace, toggle_comments_in, with_line_ending,
+    },
+    tokenizer::{BracketError, check_brackets, word_at},
+    ui::{ThemePalette, load_theme, setup_custom_style},
+    undo::{Snapshot, UndoHistory},
+    window::{WindowGeometry, current_geometry, custom_window_frame},
 };
 
 const STATE_FILE: &str = "sapf_apt_state.json";
+const STATE_FILE_ENV: &str = "SAPF_APT_STATE";
+// Bump when the saved shape changes and teach `migrate_state` the old one
+const STATE_VERSION: u64 = 1;
+const BACKUP_INTERVAL: Duration = Duration::from_secs(300);
+const EDITOR_ID: &str = "editor";
+const CONSOLE_ID: &str = "console";
 const TEXT_EDIT_MARGIN: i8 = 10;
-const DEFAULT_FONT_SIZE: f32 = 14.0;
+const COMPLETION_LIST_HEIGHT: f32 = 200.0;
+// Rows Page Up and Page Down move the completion selection by
+const COMPLETION_PAGE: usize = 10;
+const COMPLETION_DOCS_WIDTH: f32 = 300.0;
+const COMPLETION_DOCS_GAP: f32 = 4.0;
 const CHAR_WIDTH_RATIO: f32 = 0.6;
 const LINE_HEIGHT_RATIO: f32 = 1.2;
+const MIN_LIVE_DEBOUNCE_MS: u64 = 100;
+const MAX_PENDING_SENDS: usize = 256;
+const MAX_CLIPBOARD_EVAL_LEN: usize = 64 * 1024;
+const MAX_EVAL_HISTORY: usize = 500;
+const CLIPBOARD_BUFFER_NAME: &str = "clipboard";
+const FORMATTER_TIMEOUT: Duration = Duration::from_secs(5);
+const BRACKET_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+const MINIMAP_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+const MINIMAP_WIDTH: f32 = 48.0;
+const MIN_CONSOLE_HEIGHT: f32 = 60.0;
+const MIN_EDITOR_HEIGHT: f32 = 240.0;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Buffer {
+    #[serde(default)]
+    id: u64,
     content: String,
     cursor_pos: usize,
     name: String,
     is_modified: bool,
     file_path: Option<PathBuf>,
+    #[serde(default)]
+    locked: bool,
+    #[serde(default)]
+    line_ending: LineEnding,
+    #[serde(default)]
+    live: bool,
+    // The sapf connection this buffer evaluates on, the primary when `None`
+    #[serde(default)]
+    connection_id: Option<String>,
+    // Unix timestamp of the last switch to or edit of this buffer
+    #[serde(default)]
+    last_access: i64,
+    // Lines marked for firing during a performance
+    #[serde(default)]
+    armed_lines: Vec<ArmedLine>,
+    // Sent to sapf the first time the buffer becomes active, e.g. to set up an instrument
+    #[serde(default)]
+    autorun: String,
+    #[serde(skip)]
+    autorun_fired: bool,
+    // Index into `armed_lines` of the next one fire-next sends
+    #[serde(skip)]
+    next_armed: usize,
+    // Trimmed contents of the lines evaluated this session, an edited line no longer matches
+    #[serde(skip)]
+    evaluated_lines: HashSet<String>,
+    #[serde(skip)]
+    revision: u64,
+    #[serde(skip)]
+    history: UndoHistory,
+    #[serde(skip)]
+    line_index: RefCell<Option<(u64, Rc<LineIndex>)>>,
 }
 
 impl Buffer {
     fn new(name: String) -> Self {
         Self {
+            id: 0,
             content: String::new(),
             cursor_pos: 0,
             name,
             is_modified: false,
             file_path: None,
+            locked: false,
+            line_ending: LineEnding::Lf,
+            live: false,
+            connection_id: None,
+            last_access: Utc::now().timestamp(),
+            armed_lines: Vec::new(),
+            autorun: String::new(),
+            autorun_fired: false,
+            next_armed: 0,
+            evaluated_lines: HashSet::new(),
+            revision: 0,
+            history: UndoHistory::default(),
+            line_index: RefCell::new(None),
         }
     }
+
+    fn from_file(name: String, raw_content: &str, file_path: PathBuf) -> Self {
+        let (content, line_ending) = normalize_loaded_text(raw_content);
+        Self {
+            content,
+            file_path: Some(file_path),
+            line_ending,
+            ..Self::new(name)
+        }
+    }
+
+    // The saved cursor can be past the end when the content shrank in between
+    fn clamp_cursor(&mut self) -> usize {
+        self.cursor_pos = self.cursor_pos.min(self.content.chars().count());
+        self.cursor_pos
+    }
+
+    fn mark_edited(&mut self) {
+        self.revision += 1;
+        self.is_modified = true;
+        self.last_access = Utc::now().timestamp();
+        self.history.record(&self.content, self.cursor_pos);
+        self.disarm_changed_lines();
+    }
+
+    // An armed line stays armed only while the same text is at the same index
+    fn disarm_changed_lines(&mut self) {
+        if self.armed_lines.is_empty() {
+            return;
+        }
+
+        let armed = std::mem::take(&mut self.armed_lines);
+        self.armed_lines = armed
+            .into_iter()
+            .filter(|armed| self.line_text(armed.line).trim() == armed.text)
+            .collect();
+    }
+
+    fn toggle_armed_line(&mut self, line: usize) {
+        if let Some(idx) = self.armed_lines.iter().position(|armed| armed.line == line) {
+            self.armed_lines.remove(idx);
+            return;
+        }
+
+        let text = self.line_text(line).trim().to_string();
+        if text.is_empty() {
+            return;
+        }
+        let idx = self.armed_lines.partition_point(|armed| armed.line < line);
+        self.armed_lines.insert(idx, ArmedLine { line, text });
+    }
+
+    fn line_index(&self) -> Rc<LineIndex> {
+        let mut cache = self.line_index.borrow_mut();
+        if let Some((_, index)) = cache
+            .as_ref()
+            .filter(|(revision, _)| *revision == self.revision)
+        {
+            return Rc::clone(index);
+        }
+
+        let index = Rc::new(LineIndex::new(&self.content));
+        *cache = Some((self.revision, Rc::clone(&index)));
+        index
+    }
+
+    fn line_text(&self, line: usize) -> &str {
+        &self.content[self.line_index().line_range(&self.content, line)]
+    }
+}
+
+enum CompareTarget {
+    Buffer(usize),
+    SavedFile,
+}
+
+struct DiffView {
+    title: String,
+    lines: Vec<DiffLine>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ArmedLine {
+    line: usize,
+    text: String,
 }
 
 #[derive(Serialize, Deserialize)]
-struct AppState {
+pub(crate) struct AppState {
+    #[serde(default)]
+    version: u64,
     buffers: Vec<Buffer>,
     current_buffer_idx: usize,
     next_buffer_id: usize,
+    #[serde(default)]
+    pub(crate) settings: Settings,
+    #[serde(default)]
+    set_list: Vec<u64>,
+    #[serde(default)]
+    eval_history: Vec<String>,
+    #[serde(default)]
+    pub(crate) window: Option<WindowGeometry>,
+    // Action name to key, e.g. `"stop": "Ctrl+Period"`
+    #[serde(default)]
+    keybindings: BTreeMap<String, String>,
+    // Shown in the console once the app is up
+    #[serde(skip)]
+    load_warning: Option<String>,
 }
 
-impl AppState {
+// Where the last buffer eval came from, so sapf errors can be mapped back to it
+struct EvalOrigin {
+    buffer_id: u64,
+    start_line: usize,
+    line_count: usize,
+}
+
+struct DefinitionSite {
+    buffer_id: u64,
+    byte_idx: usize,
+    line: usize,
+    line_text: String,
+}
+
+struct RenameEditor {
+    buffer_id: u64,
+    name: String,
+    // Also move the buffer's file, otherwise only the tab changes
+    rename_file: bool,
+    should_focus: bool,
+}
+
+// The history entry put into the editor, so the next Ctrl+Up/Down replaces it
+struct HistoryRecall {
+    position: usize,
+    buffer_id: u64,
+    revision: u64,
+    // Char range of the recalled text
+    start: usize,
+    end: usize,
+    // The line it replaced, put back when stepping past the newest entry
+    draft: String,
+}
+
+struct ErrorMarker {
+    buffer_id: u64,
+    line: usize,
+    line_text: String,
+    message: String,
+}
+
+// Borrowing mirror of `AppState`, so saving doesn't clone every buffer
+#[derive(Serialize)]
+struct AppStateRef<'a> {
+    version: u64,
+    buffers: &'a [Buffer],
+    current_buffer_idx: usize,
+    next_buffer_id: usize,
+    settings: &'a Settings,
+    set_list: &'a [u64],
+    eval_history: &'a [String],
+    window: Option<WindowGeometry>,
+    keybindings: &'a BTreeMap<String, String>,
+}
+
+impl AppStateRef<'_> {
     fn save_to_file(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let state_path = get_state_file_path()?;
+        self.save_to_path(&get_state_file_path()?)
+    }
 
+    fn save_to_path(&self, state_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(parent) = state_path.parent() {
             fs::create_dir_all(parent)?;
         }
 
+        // Write next to the target and rename over it, so a crash never leaves half a file
         let json_data = serde_json::to_string_pretty(self)?;
-        fs::write(state_path, json_data)?;
+        let temp_path = sibling_path(state_path, "tmp");
+        fs::write(&temp_path, json_data)?;
+        backup_state(state_path);
+        fs::rename(&temp_path, state_path)?;
         Ok(())
     }
+}
+
+// Copies the state to `.bak` every few minutes and only while it still loads, so
+// the backup is a good state from a while ago rather than the last keystroke
+fn backup_state(state_path: &Path) {
+    let backup_path = sibling_path(state_path, "bak");
+    let backed_up_recently = fs::metadata(&backup_path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < BACKUP_INTERVAL);
+    if backed_up_recently {
+        return;
+    }
+
+    let loads = fs::read_to_string(state_path)
+        .ok()
+        .is_some_and(|json| serde_json::from_str::<AppState>(&json).is_ok());
+    if !loads {
+        return;
+    }
+    if let Err(e) = fs::copy(state_path, &backup_path) {
+        log::error!(
+            "Failed to back up the state to {}: {}",
+            backup_path.display(),
+            e
+        );
+    }
+}
 
-    fn load_from_file() -> Result<Self, Box<dyn std::error::Error>> {
-        let state_path = get_state_file_path()?;
+impl AppState {
+    pub(crate) fn load_from_file() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load_from_path(&get_state_file_path()?)
+    }
 
+    fn load_from_path(state_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
         if !state_path.exists() {
             return Err("State file does not exist".into());
         }
 
-        let json_data = fs::read_to_string(state_path)?;
-        let state: AppState = serde_json::from_str(&json_data)?;
+        match Self::read_state(state_path) {
+            Ok(state) => Ok(state),
+            Err(e) => {
+                let backup_path = sibling_path(state_path, "bak");
+                log::error!(
+                    "Failed to read state ({}), trying the backup {}",
+                    e,
+                    backup_path.display()
+                );
+                Self::read_state(&backup_path)
+            }
+        }
+    }
+
+    fn read_state(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let json_data = fs::read_to_string(path)?;
+        let mut value: serde_json::Value = serde_json::from_str(&json_data)?;
+        let version = value
+            .get("version")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(0);
+
+        // The next save rewrites the file in this version's shape, keep the original around
+        let mut load_warning = None;
+        if version != STATE_VERSION {
+            let copy_path = sibling_path(path, &format!("v{}", version));
+            if let Err(e) = fs::copy(path, &copy_path) {
+                log::error!(
+                    "Failed to keep a copy of the state at {}: {}",
+                    copy_path.display(),
+                    e
+                );
+            }
+            if version > STATE_VERSION {
+                load_warning = Some(format!(
+                    "the state file is from a newer version (v{}, this is v{}), settings it \
+                     doesn't know will be dropped, the original is kept at {}",
+                    version,
+                    STATE_VERSION,
+                    copy_path.display()
+                ));
+            }
+            migrate_state(&mut value, version);
+        }
+
+        let mut state: AppState = serde_json::from_value(value)?;
+        state.load_warning = load_warning;
         Ok(state)
     }
 }
 
+// Brings an older state up to `STATE_VERSION`, fields that are simply missing
+// are left to their serde defaults
+fn migrate_state(state: &mut serde_json::Value, from_version: u64) {
+    let Some(state) = state.as_object_mut() else {
+        return;
+    };
+
+    if from_version < 1 {
+        // Version 0 predates the field itself, nothing else changed shape
+        state.insert("version".to_string(), 1.into());
+    }
+}
+
+// A name that stays a single file in the same folder when renaming to it
+fn is_plain_file_name(name: &str) -> bool {
+    !name.contains(['/', '\\'])
+        && !name.contains("..")
+        && Path::new(name).file_name() == Some(OsStr::new(name))
+}
+
+// `state.json` -> `state.json.bak`
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(extension);
+    path.with_file_name(file_name)
+}
+
+// Kills the processes off the UI thread, their readers can take a moment to notice
+fn shut_down_connections(connections: Vec<SapfConnection>) {
+    if connections.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        for connection in connections {
+            connection.shutdown(&[]);
+        }
+    });
+}
+
+fn open_in_file_manager(dir: &Path) -> std::io::Result<()> {
+    let program = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(target_os = "windows") {
+        "explorer"
+    } else {
+        "xdg-open"
+    };
+
+    Command::new(program).arg(dir).spawn().map(|_| ())
+}
+
+// Docs for the active completion beside the list, on the left when the right
+// side would run off the window
+fn show_completion_docs(ctx: &egui::Context, list_rect: egui::Rect, docs: &str) {
+    if docs.is_empty() {
+        return;
+    }
+
+    let fits_right = list_rect.right() + COMPLETION_DOCS_GAP + COMPLETION_DOCS_WIDTH
+        <= ctx.screen_rect().right();
+    let (pos, pivot) = if fits_right {
+        (
+            list_rect.right_top() + egui::vec2(COMPLETION_DOCS_GAP, 0.0),
+            egui::Align2::LEFT_TOP,
+        )
+    } else {
+        (
+            list_rect.left_top() - egui::vec2(COMPLETION_DOCS_GAP, 0.0),
+            egui::Align2::RIGHT_TOP,
+        )
+    };
+
+    egui::Area::new(egui::Id::new("docs"))
+        .fixed_pos(pos)
+        .pivot(pivot)
+        .constrain(true)
+        .show(ctx, |ui| {
+            egui::Frame::new()
+                .corner_radius(5.0)
+                .inner_margin(5.0)
+                .show(ui, |ui| {
+                    ui.set_max_width(COMPLETION_DOCS_WIDTH);
+                    ui.label(docs);
+                });
+        });
+}
+
+fn dropped_file_path(dropped_file: &egui::DroppedFile) -> Option<PathBuf> {
+    let Some(file_path) = dropped_file.path.clone() else {
+        log::info!("File has no path information");
+        return None;
+    };
+
+    let extension = file_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+
+    match extension.as_deref() {
+        Some("sapf") | Some("txt") => Some(file_path),
+        Some(ext) => {
+            log::info!(
+                "Unsupported file type: .{} (only .sapf and .txt files are supported)",
+                ext
+            );
+            None
+        }
+        None => {
+            log::info!("Dropped file has no extension (only .sapf and .txt files are supported)");
+            None
+        }
+    }
+}
+
+// Tab labels that tell same-named buffers apart: the parent folder for files,
+// a counter for the rest. Stored names are left alone.
+fn display_names(buffers: &[Buffer]) -> Vec<String> {
+    let count_of = |names: &[String], name: &String| names.iter().filter(|n| *n == name).count();
+
+    let plain: Vec<String> = buffers.iter().map(|buffer| buffer.name.clone()).collect();
+    let with_dirs: Vec<String> = buffers
+        .iter()
+        .zip(&plain)
+        .map(|(buffer, name)| {
+            let parent = buffer
+                .file_path
+                .as_ref()
+                .and_then(|path| path.parent())
+                .and_then(|dir| dir.file_name())
+                .and_then(|dir| dir.to_str());
+            match parent {
+                Some(dir) if count_of(&plain, name) > 1 => format!("{} — {}", name, dir),
+                _ => name.clone(),
+            }
+        })
+        .collect();
+
+    with_dirs
+        .iter()
+        .enumerate()
+        .map(|(idx, name)| {
+            if count_of(&with_dirs, name) < 2 {
+                return name.clone();
+            }
+            let nth = with_dirs[..idx].iter().filter(|n| *n == name).count();
+            if nth == 0 {
+                name.clone()
+            } else {
+                format!("{} ({})", name, nth + 1)
+            }
+        })
+        .collect()
+}
+
+static STATE_FILE_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+// Set from the `--state` command line flag, which wins over `SAPF_APT_STATE`
+pub(crate) fn set_state_file_path(path: PathBuf) {
+    let _ = STATE_FILE_OVERRIDE.set(path);
+}
+
 fn get_state_file_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let mut path = dirs::config_dir()
-        .or_else(dirs::home_dir)
-        .ok_or("Could not find config or home directory")?;
+    if let Some(path) = STATE_FILE_OVERRIDE.get() {
+        return Ok(path.clone());
+    }
+    if let Some(path) = std::env::var_os(STATE_FILE_ENV).filter(|path| !path.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
 
-    path.push("sapf-as-plain-text");
+    let mut path = get_config_dir()?;
     path.push(STATE_FILE);
     Ok(path)
 }
 
+fn check_state_file_writable(state_path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = state_path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+
+    let existed = state_path.exists();
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_path)?;
+    if !existed {
+        fs::remove_file(state_path)?;
+    }
+    Ok(())
+}
+
 pub struct SapfAsPlainText {
     buffers: Vec<Buffer>,
     current_buffer_idx: usize,
     next_buffer_id: usize,
-    from_sapf: String,
-    from_sapf_receiver: Option<Receiver<String>>,
-    pty_writer: Option<Box<dyn Write + Send>>,
+    console: Vec<ConsoleLine>,
+    // Auto-scroll pauses while the user reads further up
+    console_follow: bool,
+    console_seen_len: usize,
+    console_jump_to_bottom: bool,
+    connections: Vec<SapfConnection>,
+    new_connection_name: String,
+    live_eval_due: Option<Instant>,
+    last_live_eval: Option<String>,
     sapf_grammar: SapfDictionary,
     completions: Vec<crate::completions_and_hints::CompletionItem>,
     hover_info: Option<String>,
+    hover_key: Option<(u64, u64, usize)>,
+    // Buffer, revision and cursor the completions were made for
+    completions_key: Option<(u64, u64, usize)>,
+    last_eval_origin: Option<EvalOrigin>,
+    error_marker: Option<ErrorMarker>,
+    // Result of the last bracket check, keyed by buffer id and revision
+    bracket_status: Option<((u64, u64), Result<(), BracketError>)>,
+    bracket_check_after: Instant,
+    autosave_after: Instant,
+    // Fingerprint of what the state file last got, see `state_fingerprint`
+    saved_fingerprint: Cell<u64>,
+    minimap_lines: Option<((u64, u64), Vec<LineOverview>)>,
+    minimap_update_after: Instant,
+    minimap_jump: Option<f32>,
+    // Scroll offset, content height and visible height of the editor
+    editor_viewport: (f32, f32, f32),
     show_completions: bool,
     should_focus_text_edit: bool,
-    last_completion_cursor: Option<usize>,
-    should_focus_completions: bool,
+    editor_has_focus: bool,
+    pending_cursor: Option<CCursorRange>,
+    // Highlighted row of the completion popup, moved with the arrow keys
+    selected_completion: usize,
+    // Set when the selection moved by keyboard, so the list scrolls to it once
+    scroll_to_completion: bool,
+    // Char ranges of the snippet placeholders Tab hasn't reached yet
+    snippet_placeholders: Vec<(usize, usize)>,
+    snippet_buffer_id: u64,
+    // Buffer length when the current placeholder was selected
+    snippet_len: usize,
     show_buffer_bar: bool,
+    // Ctrl+B keeps the buffer bar open without hovering
+    buffer_bar_pinned: bool,
+    settings: Settings,
+    show_settings: bool,
+    show_help: bool,
+    // Nothing is written to the state file for this session
+    ephemeral: bool,
+    // Shown as a pick list when a word is defined in more than one place
+    definition_choices: Vec<DefinitionSite>,
+    show_log: bool,
+    // One-off line sent straight to sapf, never stored
+    show_scratch: bool,
+    scratch_input: String,
+    should_focus_scratch: bool,
+    // Ctrl+G line number input
+    show_goto_line: bool,
+    goto_line_input: String,
+    should_focus_goto_line: bool,
+    should_focus_console: bool,
+    theme: ThemePalette,
+    // Code of the last buffer eval, re-sent by Ctrl+Shift+Enter from anywhere
+    last_eval: Option<String>,
+    last_panic: Option<String>,
+    // Lines evaluated while no sapf was running, sent once one is
+    pending_sends: VecDeque<String>,
+    // Several files being opened at once
+    file_batch: Option<FileBatch>,
+    // Buffer id and the snippet being edited for it
+    autorun_editor: Option<(u64, String)>,
+    rename_editor: Option<RenameEditor>,
+    diff_view: Option<DiffView>,
+    show_regions: bool,
+    show_search: bool,
+    search_query: String,
+    should_focus_search: bool,
+    patterns: Vec<Pattern>,
+    show_patterns: bool,
+    show_docs_search: bool,
+    docs_query: String,
+    should_focus_docs_search: bool,
+    confirm_new_session: bool,
+    // Id of the modified buffer waiting on save, discard or cancel
+    confirm_close_buffer: Option<u64>,
+    // False once any running sapf process has exited, until it's restarted
+    sapf_alive: bool,
+    confirm_buffer_cap: bool,
+    set_list: Vec<u64>,
+    set_list_position: Option<usize>,
+    show_set_list: bool,
+    // Everything sent to sapf, oldest first
+    eval_history: Vec<String>,
+    history_recall: Option<HistoryRecall>,
+    window_geometry: Option<WindowGeometry>,
+    // Set once the restored geometry has been checked against the monitor
+    window_checked: bool,
+    // As written in the state file, `keymap` is what's in effect
+    keybindings: BTreeMap<String, String>,
+    keymap: Keymap,
 }
 
 impl SapfAsPlainText {
-    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        setup_custom_style(&cc.egui_ctx);
-        let mut app = if let Ok(saved_state) = AppState::load_from_file() {
+    pub fn new(
+        cc: &eframe::CreationContext<'_>,
+        saved_state: Option<AppState>,
+        ephemeral: bool,
+    ) -> Self {
+        let (theme, theme_warnings) = load_theme();
+        setup_custom_style(&cc.egui_ctx, &theme);
+        let mut saved_state = saved_state;
+        let load_warning = saved_state
+            .as_mut()
+            .and_then(|state| state.load_warning.take());
+        let mut app = if let Some(saved_state) = saved_state {
             Self::from_saved_state(saved_state)
         } else {
             Self::with_default_state()
         };
 
+        app.ephemeral = ephemeral;
+        app.theme = theme;
+        for warning in theme_warnings {
+            app.console.push(ConsoleLine::note(warning));
+        }
+        app.load_keymap();
+        if let Some(warning) = load_warning {
+            log::error!("{}", warning);
+            app.console.push(ConsoleLine::note(warning));
+        }
+        app.assign_missing_buffer_ids();
+        if !app.ephemeral {
+            app.check_state_file();
+        }
+        // Focused so the restored caret is live, a first click would move it
+        app.restore_buffer_cursor();
+        app.should_focus_text_edit = true;
+        app.reload_patterns();
         app.run_sapf();
+        app.check_buffer_connections();
         app
     }
 
+    // Missing actions are written out with their defaults so they can be found and changed
+    fn load_keymap(&mut self) {
+        for (action, combo) in Keymap::default_bindings() {
+            self.keybindings.entry(action.to_string()).or_insert(combo);
+        }
+        let (keymap, warnings) = Keymap::load(&self.keybindings);
+        self.keymap = keymap;
+        for warning in warnings {
+            self.console.push(ConsoleLine::note(warning));
+        }
+    }
+
     fn with_default_state() -> Self {
         Self {
             buffers: vec![Buffer::new("Untitled 1".to_string())],
             current_buffer_idx: 0,
             next_buffer_id: 2,
-            from_sapf: String::new(),
-            from_sapf_receiver: None,
-            pty_writer: None,
+            console: Vec::new(),
+            console_follow: true,
+            console_seen_len: 0,
+            console_jump_to_bottom: false,
+            connections: Vec::new(),
+            new_connection_name: String::new(),
+            live_eval_due: None,
+            last_live_eval: None,
             sapf_grammar: SapfDictionary::new(),
             completions: Vec::new(),
             hover_info: None,
+            hover_key: None,
+            completions_key: None,
+            last_eval_origin: None,
+            error_marker: None,
+            bracket_status: None,
+            bracket_check_after: Instant::now(),
+            autosave_after: Instant::now(),
+            saved_fingerprint: Cell::new(0),
+            minimap_lines: None,
+            minimap_update_after: Instant::now(),
+            minimap_jump: None,
+            editor_viewport: (0.0, 0.0, 0.0),
             show_completions: false,
             should_focus_text_edit: false,
-            last_completion_cursor: None,
-            should_focus_completions: false,
+            editor_has_focus: false,
+            pending_cursor: None,
+            selected_completion: 0,
+            scroll_to_completion: false,
+            snippet_placeholders: Vec::new(),
+            snippet_buffer_id: 0,
+            snippet_len: 0,
             show_buffer_bar: false,
+            buffer_bar_pinned: false,
+            settings: Settings::default(),
+            show_settings: false,
+            show_help: false,
+            ephemeral: false,
+            definition_choices: Vec::new(),
+            show_log: false,
+            show_scratch: false,
+            scratch_input: String::new(),
+            should_focus_scratch: false,
+            show_goto_line: false,
+            goto_line_input: String::new(),
+            should_focus_goto_line: false,
+            should_focus_console: false,
+            theme: ThemePalette::default(),
+            last_eval: None,
+            last_panic: None,
+            pending_sends: VecDeque::new(),
+            file_batch: None,
+            autorun_editor: None,
+            rename_editor: None,
+            diff_view: None,
+            show_regions: false,
+            show_search: false,
+            search_query: String::new(),
+            should_focus_search: false,
+            patterns: Vec::new(),
+            show_patterns: false,
+            show_docs_search: false,
+            docs_query: String::new(),
+            should_focus_docs_search: false,
+            confirm_new_session: false,
+            confirm_close_buffer: None,
+            sapf_alive: true,
+            confirm_buffer_cap: false,
+            set_list: Vec::new(),
+            set_list_position: None,
+            show_set_list: false,
+            eval_history: Vec::new(),
+            history_recall: None,
+            window_geometry: None,
+            window_checked: false,
+            keybindings: BTreeMap::new(),
+            keymap: Keymap::default(),
         }
     }
 
     fn from_saved_state(state: AppState) -> Self {
         let buffer_count = state.buffers.len();
-        Self {
+        let mut app = Self {
             buffers: state.buffers,
             current_buffer_idx: state.current_buffer_idx.min(buffer_count.saturating_sub(1)),
             next_buffer_id: state.next_buffer_id,
-            from_sapf: String::new(),
-            from_sapf_receiver: None,
-            pty_writer: None,
+            console: Vec::new(),
+            console_follow: true,
+            console_seen_len: 0,
+            console_jump_to_bottom: false,
+            connections: Vec::new(),
+            new_connection_name: String::new(),
+            live_eval_due: None,
+            last_live_eval: None,
             sapf_grammar: SapfDictionary::new(),
             completions: Vec::new(),
             hover_info: None,
+            hover_key: None,
+            completions_key: None,
+            last_eval_origin: None,
+            error_marker: None,
+            bracket_status: None,
+            bracket_check_after: Instant::now(),
+            autosave_after: Instant::now(),
+            saved_fingerprint: Cell::new(0),
+            minimap_lines: None,
+            minimap_update_after: Instant::now(),
+            minimap_jump: None,
+            editor_viewport: (0.0, 0.0, 0.0),
             show_completions: false,
             should_focus_text_edit: false,
-            last_completion_cursor: None,
-            should_focus_completions: false,
+            editor_has_focus: false,
+            pending_cursor: None,
+            selected_completion: 0,
+            scroll_to_completion: false,
+            snippet_placeholders: Vec::new(),
+            snippet_buffer_id: 0,
+            snippet_len: 0,
             show_buffer_bar: false,
-        }
+            buffer_bar_pinned: false,
+            settings: state.settings,
+            show_settings: false,
+            show_help: false,
+            ephemeral: false,
+            definition_choices: Vec::new(),
+            show_log: false,
+            show_scratch: false,
+            scratch_input: String::new(),
+            should_focus_scratch: false,
+            show_goto_line: false,
+            goto_line_input: String::new(),
+            should_focus_goto_line: false,
+            should_focus_console: false,
+            theme: ThemePalette::default(),
+            last_eval: None,
+            last_panic: None,
+            pending_sends: VecDeque::new(),
+            file_batch: None,
+            autorun_editor: None,
+            rename_editor: None,
+            diff_view: None,
+            show_regions: false,
+            show_search: false,
+            search_query: String::new(),
+            should_focus_search: false,
+            patterns: Vec::new(),
+            show_patterns: false,
+            show_docs_search: false,
+            docs_query: String::new(),
+            should_focus_docs_search: false,
+            confirm_new_session: false,
+            confirm_close_buffer: None,
+            sapf_alive: true,
+            confirm_buffer_cap: false,
+            set_list: state.set_list,
+            set_list_position: None,
+            show_set_list: false,
+            eval_history: state.eval_history,
+            history_recall: None,
+            window_geometry: state.window,
+            window_checked: false,
+            keybindings: state.keybindings,
+            keymap: Keymap::default(),
+        };
+
+        app.assign_missing_buffer_ids();
+        app
+    }
+
+    fn check_state_file(&mut self) {
+        let message = match get_state_file_path() {
+            Ok(path) => match check_state_file_writable(&path) {
+                Ok(()) => return,
+                Err(e) => format!("state file {} isn't writable: {}", path.display(), e),
+            },
+            Err(e) => format!("couldn't find the state file: {}", e),
+        };
+        log::error!("{}", message);
+        self.console.push(ConsoleLine::note(message));
     }
 
     fn save_state(&self) {
-        let app_state = AppState {
-            buffers: self.buffers.clone(),
+        if self.ephemeral {
+            return;
+        }
+
+        let app_state = AppStateRef {
+            version: STATE_VERSION,
+            buffers: &self.buffers,
             current_buffer_idx: self.current_buffer_idx,
             next_buffer_id: self.next_buffer_id,
+            settings: &self.settings,
+            set_list: &self.set_list,
+            eval_history: &self.eval_history,
+            window: self.window_geometry,
+            keybindings: &self.keybindings,
+        };
+
+        match app_state.save_to_file() {
+            Ok(()) => self.saved_fingerprint.set(self.state_fingerprint()),
+            Err(e) => log::error!("Failed to save state: {}", e),
+        }
+    }
+
+    // Changes whenever something the state file keeps has changed, without
+    // serializing it all
+    fn state_fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for buffer in &self.buffers {
+            (buffer.id, buffer.revision, buffer.cursor_pos).hash(&mut hasher);
+            (&buffer.name, &buffer.file_path, buffer.is_modified).hash(&mut hasher);
+        }
+        (self.current_buffer_idx, &self.set_list).hash(&mut hasher);
+        (self.eval_history.len(), self.eval_history.last()).hash(&mut hasher);
+        if let Some(window) = self.window_geometry {
+            [window.x, window.y, window.width, window.height]
+                .map(f32::to_bits)
+                .hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    // Catches what changes without saving on the spot, so a crash loses at most one interval
+    fn autosave(&mut self, ctx: &egui::Context) {
+        if self.ephemeral || self.settings.autosave_secs == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        if now >= self.autosave_after {
+            if self.state_fingerprint() != self.saved_fingerprint.get() {
+                self.save_state();
+            }
+            self.autosave_after = now + Duration::from_secs(self.settings.autosave_secs);
+        }
+        ctx.request_repaint_after(self.autosave_after - now);
+    }
+
+    fn buffer_file_content(&self, buffer_idx: usize) -> String {
+        let buffer = &self.buffers[buffer_idx];
+        if self.settings.preserve_line_endings {
+            with_line_ending(&buffer.content, buffer.line_ending)
+        } else {
+            buffer.content.clone()
+        }
+    }
+
+    // Overwrites the buffer's file, only buffers that were never on disk get the dialog
+    fn save_buffer(&mut self, buffer_idx: usize) -> bool {
+        let Some(path) = self.buffers[buffer_idx].file_path.clone() else {
+            return self.export_buffer(buffer_idx);
         };
 
-        if let Err(e) = app_state.save_to_file() {
-            eprintln!("Failed to save state: {}", e);
+        let content = self.buffer_file_content(buffer_idx);
+        match std::fs::write(&path, &content) {
+            Ok(()) => {
+                self.buffers[buffer_idx].is_modified = false;
+                self.save_state();
+                log::info!(
+                    "Buffer '{}' saved to: {}",
+                    self.buffers[buffer_idx].name,
+                    path.display()
+                );
+                true
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to save buffer '{}' to {}: {}",
+                    self.buffers[buffer_idx].name,
+                    path.display(),
+                    e
+                );
+                self.console.push(ConsoleLine::note(format!(
+                    "could not save {}: {}",
+                    path.display(),
+                    e
+                )));
+                false
+            }
         }
     }
 
-    fn export_current_buffer(&mut self) {
-        let buffer_idx = self.current_buffer_idx;
-        let content = self.buffers[buffer_idx].content.clone();
+    fn export_buffer(&mut self, buffer_idx: usize) -> bool {
+        let content = self.buffer_file_content(buffer_idx);
         let buffer_name = self.buffers[buffer_idx].name.clone();
         let file_path = self.buffers[buffer_idx].file_path.clone();
         let mut dialog = rfd::FileDialog::new()
@@ -202,71 +1090,188 @@ impl SapfAsPlainText {
                 format!("{}.sapf", buffer_name)
             };
             dialog = dialog.set_file_name(&default_filename);
+            if let Some(dir) = &self.settings.last_export_dir {
+                dialog = dialog.set_directory(dir);
+            }
         }
 
-        if let Some(path) = dialog.save_file() {
-            match std::fs::write(&path, &content) {
-                Ok(()) => {
-                    let current_buffer = &mut self.buffers[buffer_idx];
-                    current_buffer.file_path = Some(path.clone());
-                    current_buffer.is_modified = false;
+        let Some(path) = dialog.save_file() else {
+            return false;
+        };
 
-                    if current_buffer.name.starts_with("Untitled ") {
-                        if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-                            current_buffer.name = filename.to_string();
-                        }
-                    }
+        self.settings.last_export_dir = path.parent().map(Path::to_path_buf);
+        match std::fs::write(&path, &content) {
+            Ok(()) => {
+                let current_buffer = &mut self.buffers[buffer_idx];
+                current_buffer.file_path = Some(path.clone());
+                current_buffer.is_modified = false;
 
-                    let final_name = current_buffer.name.clone();
-                    self.save_state();
-                    println!("Buffer '{}' saved to: {}", final_name, path.display());
-                }
-                Err(e) => {
-                    eprintln!(
-                        "Failed to save buffer '{}' to {}: {}",
-                        buffer_name,
-                        path.display(),
-                        e
-                    );
+                if current_buffer.name.starts_with("Untitled ")
+                    && let Some(filename) = path.file_name().and_then(|n| n.to_str())
+                {
+                    current_buffer.name = filename.to_string();
                 }
+
+                let final_name = current_buffer.name.clone();
+                self.save_state();
+                log::info!("Buffer '{}' saved to: {}", final_name, path.display());
+                true
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to save buffer '{}' to {}: {}",
+                    buffer_name,
+                    path.display(),
+                    e
+                );
+                false
             }
         }
     }
 
     fn load_file_into_new_buffer(&mut self) {
-        if let Some(path) = rfd::FileDialog::new()
+        let mut dialog = rfd::FileDialog::new()
             .set_title("Open File...")
             .add_filter("SAPF Files", &["sapf"])
             .add_filter("Text Files", &["txt"])
-            .add_filter("All Files", &["*"])
-            .pick_file()
-        {
-            match std::fs::read_to_string(&path) {
-                Ok(content) => {
-                    let filename = path
-                        .file_name()
-                        .and_then(|n| n.to_str())
-                        .unwrap_or("Untitled")
-                        .to_string();
-
-                    let buffer = Buffer {
-                        content,
-                        cursor_pos: 0,
-                        name: filename,
-                        is_modified: false,
-                        file_path: Some(path.clone()),
-                    };
+            .add_filter("All Files", &["*"]);
+        let buffer_dir = self
+            .get_current_buffer()
+            .file_path
+            .as_ref()
+            .and_then(|path| path.parent());
+        if let Some(dir) = buffer_dir.or(self.settings.last_open_dir.as_deref()) {
+            dialog = dialog.set_directory(dir);
+        }
 
-                    self.buffers.push(buffer);
-                    self.current_buffer_idx = self.buffers.len() - 1;
-                    self.should_focus_text_edit = true;
-                    self.save_state();
+        if let Some(paths) = dialog.pick_files() {
+            if let Some(path) = paths.first() {
+                self.settings.last_open_dir = path.parent().map(Path::to_path_buf);
+            }
+            self.open_files(paths);
+        }
+    }
+
+    fn open_files(&mut self, mut paths: Vec<PathBuf>) {
+        if paths.len() > 1 {
+            if self.file_batch.is_some() {
+                self.console.push(ConsoleLine::note(
+                    "still opening the previous files, try again when they're done".to_string(),
+                ));
+                return;
+            }
+            self.file_batch = Some(FileBatch::start(paths));
+            return;
+        }
+
+        let Some(path) = paths.pop() else {
+            return;
+        };
+        if let Some(content) = self.read_text_file(&path) {
+            self.add_buffer(Buffer::from_file(file_name(&path), &content, path.clone()));
+            log::info!("Loaded file: {}", path.display());
+        }
+    }
 
-                    println!("Loaded file: {}", path.display());
+    // Takes in what the batch thread has read so far, without the per-file prompts
+    fn process_file_batch(&mut self, ctx: &egui::Context) {
+        let Some(mut batch) = self.file_batch.take() else {
+            return;
+        };
+
+        for loaded in batch.poll() {
+            let name = file_name(&loaded.path);
+            let content = match loaded.bytes.map(|bytes| decode_text(&bytes)) {
+                Ok(DecodedText::Utf8(text)) => text,
+                Ok(DecodedText::Lossy(text)) => {
+                    self.console.push(ConsoleLine::note(format!(
+                        "{} wasn't valid UTF-8, invalid bytes were replaced",
+                        name
+                    )));
+                    text
+                }
+                Ok(DecodedText::Binary) => {
+                    batch.skipped.push(format!("{} (binary)", name));
+                    continue;
                 }
                 Err(e) => {
-                    eprintln!("Failed to load file {}: {}", path.display(), e);
+                    log::error!("Failed to load file {}: {}", loaded.path.display(), e);
+                    batch.skipped.push(format!("{} ({})", name, e));
+                    continue;
+                }
+            };
+
+            batch.opened += 1;
+            let mut buffer = Buffer::from_file(name, &content, loaded.path);
+            buffer.id = self.next_buffer_uid();
+            self.inherit_autorun(&mut buffer);
+            self.buffers.push(buffer);
+        }
+
+        if !batch.is_finished() {
+            self.file_batch = Some(batch);
+            ctx.request_repaint();
+            return;
+        }
+
+        self.console.push(ConsoleLine::note(batch.summary()));
+        if batch.opened == 0 {
+            return;
+        }
+        self.current_buffer_idx = self.buffers.len() - 1;
+        self.restore_buffer_cursor();
+        self.should_focus_text_edit = true;
+        self.save_state();
+
+        let max_buffers = self.settings.max_buffers;
+        if max_buffers > 0 && self.buffers.len() > max_buffers {
+            self.confirm_buffer_cap = true;
+        }
+    }
+
+    // Asks before opening a file that isn't valid UTF-8, refuses binary ones
+    fn read_text_file(&mut self, path: &Path) -> Option<String> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::error!("Failed to load file {}: {}", path.display(), e);
+                self.console.push(ConsoleLine::note(format!(
+                    "couldn't open {}: {}",
+                    path.display(),
+                    e
+                )));
+                return None;
+            }
+        };
+
+        match decode_text(&bytes) {
+            DecodedText::Utf8(text) => Some(text),
+            DecodedText::Lossy(text) => {
+                let answer = rfd::MessageDialog::new()
+                    .set_level(rfd::MessageLevel::Warning)
+                    .set_title("Not valid UTF-8")
+                    .set_description(format!(
+                        "{} isn't valid UTF-8. Open it anyway, replacing the invalid bytes?",
+                        path.display()
+                    ))
+                    .set_buttons(rfd::MessageButtons::YesNo)
+                    .show();
+                if answer != rfd::MessageDialogResult::Yes {
+                    return None;
                 }
+
+                self.console.push(ConsoleLine::note(format!(
+                    "{} wasn't valid UTF-8, invalid bytes were replaced",
+                    path.display()
+                )));
+                Some(text)
+            }
+            DecodedText::Binary => {
+                self.console.push(ConsoleLine::note(format!(
+                    "{} looks like a binary file, not opening it",
+                    path.display()
+                )));
+                None
             }
         }
     }
@@ -281,611 +1286,3557 @@ impl SapfAsPlainText {
 
     fn create_new_buffer(&mut self) {
         let buffer_name = format!("Untitled {}", self.next_buffer_id);
-        self.buffers.push(Buffer::new(buffer_name));
-        self.current_buffer_idx = self.buffers.len() - 1;
         self.next_buffer_id += 1;
+        self.add_buffer(Buffer::new(buffer_name));
+    }
+
+    fn add_buffer(&mut self, mut buffer: Buffer) {
+        buffer.id = self.next_buffer_uid();
+        self.inherit_autorun(&mut buffer);
+        self.buffers.push(buffer);
+        self.current_buffer_idx = self.buffers.len() - 1;
+        self.restore_buffer_cursor();
         self.should_focus_text_edit = true;
         self.save_state();
-    }
 
-    fn close_current_buffer(&mut self) {
-        if self.buffers.len() > 1 {
-            self.buffers.remove(self.current_buffer_idx);
-            if self.current_buffer_idx >= self.buffers.len() {
-                self.current_buffer_idx = self.buffers.len() - 1;
-            }
-            self.should_focus_text_edit = true;
-            self.save_state();
+        let max_buffers = self.settings.max_buffers;
+        if max_buffers > 0 && self.buffers.len() > max_buffers {
+            self.confirm_buffer_cap = true;
         }
     }
 
-    fn close_buffer(&mut self, idx: usize) {
-        if self.buffers.len() > 1 {
-            self.buffers.remove(idx);
-            if self.current_buffer_idx >= self.buffers.len() {
-                self.current_buffer_idx = self.buffers.len() - 1;
-            }
-            self.should_focus_text_edit = true;
-            self.save_state();
-        }
+    // Unmodified buffers that could be closed to get back under the cap, least recently used first
+    fn buffer_cap_candidates(&self) -> Vec<u64> {
+        let excess = self.buffers.len().saturating_sub(self.settings.max_buffers);
+        let current_id = self.get_current_buffer().id;
+        let mut candidates: Vec<&Buffer> = self
+            .buffers
+            .iter()
+            .filter(|buffer| !buffer.is_modified && buffer.id != current_id)
+            .collect();
+        candidates.sort_by_key(|buffer| buffer.last_access);
+        candidates
+            .into_iter()
+            .take(excess)
+            .map(|buffer| buffer.id)
+            .collect()
     }
 
-    fn switch_to_buffer(&mut self, idx: usize) {
-        if idx < self.buffers.len() {
-            self.current_buffer_idx = idx;
-            self.should_focus_text_edit = true;
-            self.save_state();
+    fn close_buffers_by_id(&mut self, ids: &[u64]) {
+        let current_id = self.get_current_buffer().id;
+        self.buffers.retain(|buffer| !ids.contains(&buffer.id));
+        if self.buffers.is_empty() {
+            self.buffers.push(Buffer::new("Untitled 1".to_string()));
+            self.assign_missing_buffer_ids();
         }
+        self.current_buffer_idx = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.id == current_id)
+            .unwrap_or(0);
+        self.prune_set_list();
+        self.restore_editor_state();
+        self.save_state();
     }
 
-    fn next_buffer(&mut self) {
-        if self.buffers.len() > 1 {
-            self.current_buffer_idx = (self.current_buffer_idx + 1) % self.buffers.len();
-            self.should_focus_text_edit = true;
-            self.save_state();
+    // Reopening a file keeps the autorun snippet of the buffer it was open in before
+    fn inherit_autorun(&self, buffer: &mut Buffer) {
+        if !buffer.autorun.is_empty() || buffer.file_path.is_none() {
+            return;
+        }
+        if let Some(existing) = self
+            .buffers
+            .iter()
+            .find(|existing| existing.file_path == buffer.file_path && !existing.autorun.is_empty())
+        {
+            buffer.autorun = existing.autorun.clone();
         }
     }
 
-    fn prev_buffer(&mut self) {
-        if self.buffers.len() > 1 {
-            self.current_buffer_idx = if self.current_buffer_idx == 0 {
-                self.buffers.len() - 1
-            } else {
-                self.current_buffer_idx - 1
-            };
-            self.should_focus_text_edit = true;
-            self.save_state();
+    // Fires once per buffer per session, switching back to it doesn't send it again
+    fn run_pending_autorun(&mut self) {
+        let buffer = self.get_current_buffer_mut();
+        if buffer.autorun_fired {
+            return;
+        }
+        buffer.autorun_fired = true;
+        if buffer.autorun.trim().is_empty() {
+            return;
         }
-    }
-
-    fn run_sapf(&mut self) {
-        let pty = native_pty_system();
-
-        let pty_pair = pty
-            .openpty(PtySize {
-                rows: 24,
-                cols: 80,
-                pixel_width: 0,
-                pixel_height: 0,
-            })
-            .unwrap();
-
-        let cmd = CommandBuilder::new("sapf");
-
-        // not my terminology!
-        let _ = pty_pair.slave.spawn_command(cmd).unwrap();
-        let master = pty_pair.master;
-        // ...
-
-        let (output_sender, output_receiver) = mpsc::channel::<String>();
-        let reader = master.try_clone_reader().unwrap();
-        let writer = master.take_writer().unwrap();
-
-        thread::spawn(move || {
-            let mut reader = BufReader::new(reader);
-            let mut line = String::new();
-
-            loop {
-                line.clear();
-                match reader.read_line(&mut line) {
-                    Ok(0) => break,
-                    Ok(_) => {
-                        let trimmed = line.trim_end().to_string();
-                        if !trimmed.is_empty() {
-                            eprintln!("{:?}", trimmed);
-                            if output_sender.send(trimmed).is_err() {
-                                break;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error reading from PTY: {}", e);
-                        break;
-                    }
-                }
-            }
-        });
-
-        self.pty_writer = Some(writer);
-        self.from_sapf_receiver = Some(output_receiver);
 
-        thread::sleep(Duration::from_millis(1000));
+        let note = format!("autorun for {}", buffer.name);
+        let code = clean_eval_code(&buffer.autorun);
+        self.console.push(ConsoleLine::note(note));
+        self.send_to_sapf(&code);
     }
 
-    fn send_to_sapf(&mut self, code: &str) {
-        if let Some(ref mut writer) = self.pty_writer {
-            println!("Sending to SAPF: {}", code);
-            if let Err(e) = writeln!(writer, "{}", code) {
-                eprintln!("Failed to send to SAPF: {}", e);
-            } else if let Err(e) = writer.flush() {
-                eprintln!("Failed to flush PTY: {}", e);
-            } else {
-                println!("Sent: {}", code.trim());
-            }
-        } else {
-            println!("SAPF not connected");
+    fn edit_buffer_autorun(&mut self, idx: usize) {
+        if let Some(buffer) = self.buffers.get(idx) {
+            self.autorun_editor = Some((buffer.id, buffer.autorun.clone()));
         }
     }
 
-    fn update_output(&mut self) {
-        if let Some(receiver) = &self.from_sapf_receiver {
-            while let Ok(line) = receiver.try_recv() {
-                if !line.trim().is_empty() {
-                    self.from_sapf.push_str(&line);
-                    self.from_sapf.push('\n');
-                }
-            }
+    fn edit_buffer_name(&mut self, idx: usize) {
+        if let Some(buffer) = self.buffers.get(idx) {
+            self.rename_editor = Some(RenameEditor {
+                buffer_id: buffer.id,
+                name: buffer.name.clone(),
+                rename_file: false,
+                should_focus: true,
+            });
         }
     }
 
-    fn get_current_line(&self) -> String {
-        let cursor_pos = self.get_current_buffer().cursor_pos;
-        let lines: Vec<&str> = self.get_current_buffer().content.lines().collect();
-        let mut char_count = 0;
+    fn show_rename_window(&mut self, ctx: &egui::Context) {
+        let Some(editor) = &mut self.rename_editor else {
+            return;
+        };
+        let Some(idx) = self.buffers.iter().position(|b| b.id == editor.buffer_id) else {
+            self.rename_editor = None;
+            return;
+        };
 
-        for line in &lines {
-            let line_end = char_count + line.len();
-            if cursor_pos >= char_count && cursor_pos <= line_end + 1 {
-                return line.to_string();
-            }
-            char_count = line_end + 1;
-        }
+        let has_file = self.buffers[idx].file_path.is_some();
+        let mut open = true;
+        let mut rename = false;
+        egui::Window::new("rename buffer")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                let response = ui.text_edit_singleline(&mut editor.name);
+                if editor.should_focus {
+                    response.request_focus();
+                    editor.should_focus = false;
+                }
+                if response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
+                    rename = true;
+                }
+                ui.add_enabled(
+                    has_file,
+                    egui::Checkbox::new(&mut editor.rename_file, "rename the file on disk too"),
+                )
+                .on_disabled_hover_text("The buffer isn't saved to a file");
+                if ui.button("rename").clicked() {
+                    rename = true;
+                }
+            });
 
-        if let Some(last_line) = lines.last() {
-            return last_line.to_string();
+        if rename {
+            if let Some(editor) = self.rename_editor.take() {
+                self.rename_buffer(idx, editor.name.trim(), editor.rename_file);
+            }
+        } else if !open {
+            self.rename_editor = None;
         }
-
-        self.get_current_buffer()
-            .content
-            .lines()
-            .find(|line| !line.trim().is_empty())
-            .unwrap_or("")
-            .to_string()
     }
 
-    // TODO Convoluted, need to fix edge cases
-    fn get_block_at_cursor(&self) -> Option<String> {
-        let content = &self.get_current_buffer().content;
-        let cursor_pos = self.get_current_buffer().cursor_pos;
+    // Only the tab changes unless `rename_file`, then the file moves next to where it was
+    fn rename_buffer(&mut self, idx: usize, name: &str, rename_file: bool) {
+        if name.is_empty() {
+            return;
+        }
+        if !is_plain_file_name(name) {
+            self.console.push(ConsoleLine::note(format!(
+                "Not renamed, \"{}\" has to be a plain file name without folders",
+                name
+            )));
+            return;
+        }
 
-        if cursor_pos > content.len() {
-            return None;
+        let path = self.buffers[idx].file_path.clone();
+        let move_file = rename_file
+            && path
+                .as_ref()
+                .is_some_and(|path| path.file_name() != Some(OsStr::new(name)));
+        if !move_file && name == self.buffers[idx].name {
+            return;
         }
 
-        if let Some((start, end)) = self.find_innermost_block(content, cursor_pos) {
-            let statement = self.extend_to_complete_statement(content, start, end);
-            return Some(statement.trim().to_string());
+        if let Some(path) = path.filter(|_| move_file) {
+            let new_path = path.with_file_name(name);
+            if new_path.exists() {
+                self.console.push(ConsoleLine::note(format!(
+                    "Not renamed, {} already exists",
+                    new_path.display()
+                )));
+                return;
+            }
+            if let Err(e) = fs::rename(&path, &new_path) {
+                log::error!("Failed to rename {}: {}", path.display(), e);
+                self.console.push(ConsoleLine::note(format!(
+                    "Failed to rename {}: {}",
+                    path.display(),
+                    e
+                )));
+                return;
+            }
+            self.buffers[idx].file_path = Some(new_path);
         }
 
-        None
+        self.buffers[idx].name = name.to_string();
+        self.save_state();
     }
 
-    fn find_innermost_block(&self, content: &str, cursor_pos: usize) -> Option<(usize, usize)> {
-        let chars: Vec<char> = content.chars().collect();
-        let mut best_block: Option<(usize, usize)> = None;
-        let mut stack = Vec::new();
-
-        let mut i = 0;
-        while i < chars.len() {
-            let ch = chars[i];
-
-            if ch == '\\' && i + 1 < chars.len() && chars[i + 1] == '[' {
-                stack.push((i, ']'));
-                i += 2;
-                continue;
-            }
+    fn show_autorun_window(&mut self, ctx: &egui::Context) {
+        let Some((buffer_id, _)) = &self.autorun_editor else {
+            return;
+        };
+        let buffer_id = *buffer_id;
+        let Some(idx) = self.buffers.iter().position(|b| b.id == buffer_id) else {
+            self.autorun_editor = None;
+            return;
+        };
 
-            match ch {
-                '(' => {
-                    stack.push((i, ')'));
-                }
-                '{' => {
-                    stack.push((i, '}'));
-                }
-                '[' => {
-                    stack.push((i, ']'));
+        let title = format!("autorun for {}", self.buffers[idx].name);
+        let font_size = self.settings.font_size;
+        let mut open = true;
+        let mut save = false;
+        let mut run_now = false;
+        egui::Window::new(title)
+            .id(egui::Id::new("autorun_editor"))
+            .open(&mut open)
+            .collapsible(false)
+            .default_width(420.0)
+            .show(ctx, |ui| {
+                ui.weak("Sent to sapf when this buffer is first opened or switched to");
+                if let Some((_, snippet)) = &mut self.autorun_editor {
+                    ui.add(
+                        egui::TextEdit::multiline(snippet)
+                            .font(egui::FontId::monospace(font_size))
+                            .desired_rows(6)
+                            .desired_width(f32::INFINITY),
+                    );
                 }
-                ')' | '}' | ']' => {
-                    if let Some((start, expected_close)) = stack.pop() {
-                        if ch == expected_close
-                            && start <= cursor_pos
-                            && cursor_pos <= i
-                            && (best_block.is_none()
-                                || (i - start) < (best_block.unwrap().1 - best_block.unwrap().0))
-                        {
-                            best_block = Some((start, i));
-                        }
+                ui.horizontal(|ui| {
+                    if ui.button("save").clicked() {
+                        save = true;
+                    }
+                    if ui.button("save and run now").clicked() {
+                        save = true;
+                        run_now = true;
                     }
+                });
+            });
+
+        if save {
+            if let Some((_, snippet)) = self.autorun_editor.take() {
+                let buffer = &mut self.buffers[idx];
+                buffer.autorun = snippet.trim_end().to_string();
+                // Setting it up on the open buffer shouldn't also fire it on the next switch
+                buffer.autorun_fired = !run_now;
+                if run_now {
+                    self.switch_to_buffer(idx);
+                } else {
+                    self.save_state();
                 }
-                _ => {}
             }
-
-            i += 1;
+        } else if !open {
+            self.autorun_editor = None;
         }
-
-        best_block
     }
 
-    fn extend_to_complete_statement(
-        &self,
-        content: &str,
-        block_start: usize,
-        block_end: usize,
-    ) -> String {
-        let chars: Vec<char> = content.chars().collect();
+    fn next_buffer_uid(&self) -> u64 {
+        self.buffers
+            .iter()
+            .map(|buffer| buffer.id)
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
 
-        let mut start = block_start;
-        for i in (0..block_start).rev() {
-            match chars[i] {
-                '\n' => {
-                    let line_has_content = (i + 1..block_start).any(|j| !chars[j].is_whitespace());
-                    if line_has_content {
-                        start = i + 1;
-                    }
-                    break;
-                }
-                ';' | ')' | '}' | ']' => {
-                    start = i + 1;
-                    break;
-                }
-                _ => {}
+    fn assign_missing_buffer_ids(&mut self) {
+        let mut seen = HashSet::new();
+        for idx in 0..self.buffers.len() {
+            if self.buffers[idx].id == 0 || !seen.insert(self.buffers[idx].id) {
+                self.buffers[idx].id = self.next_buffer_uid();
+                seen.insert(self.buffers[idx].id);
             }
         }
+    }
 
-        let mut end = block_end;
-        let mut i = block_end + 1;
-        while i < chars.len() {
-            match chars[i] {
-                ' ' | '\t' => {
-                    i += 1;
-                    continue;
-                }
-                '\n' | ';' => break,
-                '(' | '{' | '[' if i > block_end + 1 => break,
-                c if !c.is_whitespace() => end = i,
-                _ => {}
-            }
-            i += 1;
+    // Buffers with unsaved changes ask first
+    fn request_close_buffer(&mut self, idx: usize) {
+        if self.buffers.len() <= 1 {
+            return;
         }
 
-        let statement: String = chars[start..=end].iter().collect();
-        statement.trim().to_string()
+        if self.buffers[idx].is_modified {
+            self.confirm_close_buffer = Some(self.buffers[idx].id);
+        } else {
+            self.close_buffer(idx);
+        }
     }
 
-    fn get_code_to_send(&self) -> String {
-        if let Some(block) = self.get_block_at_cursor() {
-            return block;
+    fn close_buffer(&mut self, idx: usize) {
+        if self.buffers.len() > 1 {
+            self.buffers.remove(idx);
+            if self.current_buffer_idx >= self.buffers.len() {
+                self.current_buffer_idx = self.buffers.len() - 1;
+            }
+            self.prune_set_list();
+            self.restore_editor_state();
+            self.save_state();
         }
+    }
 
-        self.get_current_line()
+    fn add_to_set_list(&mut self, idx: usize) {
+        if let Some(buffer) = self.buffers.get(idx) {
+            self.set_list.push(buffer.id);
+            self.save_state();
+        }
     }
 
-    fn handle_key_input(&mut self, ctx: &egui::Context) {
-        ctx.input(|i| {
-            if i.key_pressed(Key::Enter) && i.modifiers.ctrl {
-                println!("{}", self.get_current_line());
-                let code = self.get_code_to_send();
-                if !code.trim().is_empty() {
-                    self.send_to_sapf(&code);
-                }
-            }
+    fn prune_set_list(&mut self) {
+        let buffers = &self.buffers;
+        self.set_list
+            .retain(|id| buffers.iter().any(|buffer| buffer.id == *id));
+        self.set_list_position = match self.set_list.len() {
+            0 => None,
+            len => self.set_list_position.map(|position| position.min(len - 1)),
+        };
+    }
 
-            if i.key_pressed(Key::Period) && i.modifiers.ctrl {
-                self.send_to_sapf("stop");
-            }
+    fn go_to_set_list_entry(&mut self, position: usize) {
+        let Some(&id) = self.set_list.get(position) else {
+            return;
+        };
 
-            if i.key_pressed(Key::E) && i.modifiers.ctrl {
-                self.send_to_sapf("stop");
-                let code = self.get_current_line();
-                if !code.trim().is_empty() {
-                    self.send_to_sapf(&code);
-                }
-            }
+        if let Some(idx) = self.buffers.iter().position(|buffer| buffer.id == id) {
+            self.set_list_position = Some(position);
+            self.switch_to_buffer(idx);
+            self.should_focus_text_edit = true;
+        }
+    }
 
-            if i.key_pressed(Key::D) && i.modifiers.ctrl {
-                self.send_to_sapf("clear");
-            }
+    fn next_set_list_entry(&mut self) {
+        if self.set_list.is_empty() {
+            return;
+        }
+        let position = match self.set_list_position {
+            Some(position) => (position + 1).min(self.set_list.len() - 1),
+            None => 0,
+        };
+        self.go_to_set_list_entry(position);
+    }
 
-            if i.key_pressed(Key::P) && i.modifiers.ctrl {
-                self.send_to_sapf("prstk");
-            }
+    fn prev_set_list_entry(&mut self) {
+        if self.set_list.is_empty() {
+            return;
+        }
+        let position = match self.set_list_position {
+            Some(position) => position.saturating_sub(1),
+            None => 0,
+        };
+        self.go_to_set_list_entry(position);
+    }
 
-            if i.key_pressed(Key::R) && i.modifiers.ctrl {
-                let code = self.get_current_line();
-                let date = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-                let buffer_name = &self.buffers[self.current_buffer_idx].name;
-                let file_name = format!("{}-{}", buffer_name, date);
-                let combined = format!("{} \"{}\" record", code, file_name);
-                self.send_to_sapf(&combined);
-            }
+    fn toggle_buffer_lock(&mut self, idx: usize) {
+        if let Some(buffer) = self.buffers.get_mut(idx) {
+            buffer.locked = !buffer.locked;
+            self.save_state();
+        }
+    }
 
-            if i.key_pressed(Key::Tab) && i.modifiers.ctrl {
-                self.trigger_completions();
-                self.should_focus_completions = true;
-            }
+    fn reveal_buffer_in_file_manager(&mut self, idx: usize) {
+        let Some(dir) = self.buffers[idx]
+            .file_path
+            .as_ref()
+            .and_then(|path| path.parent())
+            .map(Path::to_path_buf)
+        else {
+            return;
+        };
 
-            if i.key_pressed(Key::T) && i.modifiers.ctrl {
-                self.create_new_buffer();
-            }
+        if let Err(e) = open_in_file_manager(&dir) {
+            log::error!("Failed to open {}: {}", dir.display(), e);
+            self.console.push(ConsoleLine::note(format!(
+                "could not open {}: {}",
+                dir.display(),
+                e
+            )));
+        }
+    }
 
-            if i.key_pressed(Key::W) && i.modifiers.ctrl {
-                self.close_current_buffer();
+    fn compare_buffer(&mut self, idx: usize, target: CompareTarget) {
+        let buffer = &self.buffers[idx];
+        let (title, other) = match target {
+            CompareTarget::Buffer(other_idx) => {
+                let other = &self.buffers[other_idx];
+                (
+                    format!("{} → {}", other.name, buffer.name),
+                    other.content.clone(),
+                )
             }
-
-            if i.key_pressed(Key::S) && i.modifiers.ctrl {
-                self.export_current_buffer();
+            CompareTarget::SavedFile => {
+                let Some(path) = &buffer.file_path else {
+                    return;
+                };
+                match fs::read_to_string(path) {
+                    Ok(raw) => (
+                        format!("{} on disk → {}", buffer.name, buffer.name),
+                        normalize_loaded_text(&raw).0,
+                    ),
+                    Err(e) => {
+                        let message = format!("could not read {}: {}", path.display(), e);
+                        self.console.push(ConsoleLine::note(message));
+                        return;
+                    }
+                }
             }
+        };
 
-            if i.key_pressed(Key::O) && i.modifiers.ctrl {
-                self.load_file_into_new_buffer();
-            }
+        let lines = diff_lines(&other, &buffer.content);
+        self.diff_view = Some(DiffView { title, lines });
+    }
 
-            if i.key_pressed(Key::Tab) && i.modifiers.alt {
-                self.next_buffer();
-            }
+    fn show_diff_window(&mut self, ctx: &egui::Context) {
+        let Some(view) = &self.diff_view else {
+            return;
+        };
 
-            if i.key_pressed(Key::Tab) && i.modifiers.shift && i.modifiers.alt {
-                self.prev_buffer();
-            }
-        });
+        let mut open = true;
+        egui::Window::new(&view.title)
+            .id(egui::Id::new("diff_view"))
+            .open(&mut open)
+            .collapsible(false)
+            .default_size([560.0, 400.0])
+            .show(ctx, |ui| {
+                let added = view.lines.iter().filter(|l| l.kind == DiffKind::Added);
+                let removed = view.lines.iter().filter(|l| l.kind == DiffKind::Removed);
+                let (added, removed) = (added.count(), removed.count());
+                if added == 0 && removed == 0 {
+                    ui.weak("no differences");
+                    return;
+                }
+                ui.weak(format!("{} added, {} removed", added, removed));
+
+                let added_color = if ui.visuals().dark_mode {
+                    egui::Color32::LIGHT_GREEN
+                } else {
+                    egui::Color32::DARK_GREEN
+                };
+                let removed_color = ui.visuals().error_fg_color;
+                egui::ScrollArea::both()
+                    .auto_shrink([false, false])
+                    .show(ui, |ui| {
+                        for line in &view.lines {
+                            let text = match line.kind {
+                                DiffKind::Same => {
+                                    egui::RichText::new(format!("  {}", line.text)).weak()
+                                }
+                                DiffKind::Added => egui::RichText::new(format!("+ {}", line.text))
+                                    .color(added_color)
+                                    .background_color(added_color.gamma_multiply(0.15)),
+                                DiffKind::Removed => {
+                                    egui::RichText::new(format!("- {}", line.text))
+                                        .color(removed_color)
+                                        .background_color(removed_color.gamma_multiply(0.15))
+                                }
+                            };
+                            ui.label(text.monospace());
+                        }
+                    });
+            });
+
+        if !open {
+            self.diff_view = None;
+        }
     }
 
-    fn handle_file_drops(&mut self, ctx: &egui::Context) {
-        ctx.input(|i| {
-            if !i.raw.dropped_files.is_empty() {
-                for file in &i.raw.dropped_files {
-                    self.load_dropped_file(file);
-                }
-            }
-        });
+    fn toggle_buffer_live(&mut self, idx: usize) {
+        if let Some(buffer) = self.buffers.get_mut(idx) {
+            buffer.live = !buffer.live;
+            self.live_eval_due = None;
+            self.last_live_eval = None;
+            self.save_state();
+        }
     }
 
-    fn load_dropped_file(&mut self, dropped_file: &egui::DroppedFile) {
-        let file_path = if let Some(ref path) = dropped_file.path {
-            path.clone()
-        } else {
-            println!("File has no path information");
-            return;
-        };
-
-        let extension = file_path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| ext.to_lowercase());
-
-        match extension.as_deref() {
-            Some("sapf") | Some("txt") => match std::fs::read_to_string(&file_path) {
-                Ok(content) => {
-                    let filename = file_path
-                        .file_name()
-                        .and_then(|n| n.to_str())
-                        .unwrap_or("Untitled")
-                        .to_string();
-
-                    let buffer = Buffer {
-                        content,
-                        cursor_pos: 0,
-                        name: filename,
-                        is_modified: false,
-                        file_path: Some(file_path.clone()),
-                    };
+    fn switch_to_buffer(&mut self, idx: usize) {
+        if idx < self.buffers.len() {
+            self.current_buffer_idx = idx;
+            self.restore_editor_state();
+            self.save_state();
+        }
+    }
 
-                    self.buffers.push(buffer);
-                    self.current_buffer_idx = self.buffers.len() - 1;
-                    self.should_focus_text_edit = true;
-                    self.save_state();
-                }
-                Err(e) => {
-                    eprintln!("Failed to load dropped file {}: {}", file_path.display(), e);
+    fn next_buffer(&mut self) {
+        if self.buffers.len() > 1 {
+            self.current_buffer_idx = (self.current_buffer_idx + 1) % self.buffers.len();
+            self.restore_editor_state();
+            self.save_state();
+        }
+    }
+
+    fn prev_buffer(&mut self) {
+        if self.buffers.len() > 1 {
+            self.current_buffer_idx = if self.current_buffer_idx == 0 {
+                self.buffers.len() - 1
+            } else {
+                self.current_buffer_idx - 1
+            };
+            self.restore_editor_state();
+            self.save_state();
+        }
+    }
+
+    // Moves a buffer to another place in the tab bar, the current buffer stays current
+    fn move_buffer(&mut self, buffer_id: u64, to: usize) {
+        let Some(from) = self.buffers.iter().position(|b| b.id == buffer_id) else {
+            return;
+        };
+        if from == to || to >= self.buffers.len() {
+            return;
+        }
+
+        let current_id = self.get_current_buffer().id;
+        let buffer = self.buffers.remove(from);
+        self.buffers.insert(to, buffer);
+        if let Some(idx) = self.buffers.iter().position(|b| b.id == current_id) {
+            self.current_buffer_idx = idx;
+        }
+        self.save_state();
+    }
+
+    fn request_new_session(&mut self) {
+        if self.buffers.iter().any(|buffer| buffer.is_modified) {
+            self.confirm_new_session = true;
+        } else {
+            self.start_new_session();
+        }
+    }
+
+    fn start_new_session(&mut self) {
+        self.buffers = vec![Buffer::new("Untitled 1".to_string())];
+        self.assign_missing_buffer_ids();
+        self.current_buffer_idx = 0;
+        self.next_buffer_id = 2;
+        self.set_list.clear();
+        self.set_list_position = None;
+        self.set_cursor(0);
+
+        if self.settings.restart_sapf_on_new_session {
+            self.restart_sapf();
+        }
+
+        self.save_state();
+    }
+
+    fn reload_theme(&mut self, ctx: &egui::Context) {
+        let (theme, warnings) = load_theme();
+        setup_custom_style(ctx, &theme);
+        self.theme = theme;
+        if warnings.is_empty() {
+            self.console
+                .push(ConsoleLine::note("theme reloaded".to_string()));
+        }
+        for warning in warnings {
+            self.console.push(ConsoleLine::note(warning));
+        }
+    }
+
+    fn reload_patterns(&mut self) {
+        match load_patterns() {
+            Ok(patterns) => self.patterns = patterns,
+            Err(e) => log::error!("Failed to load patterns: {}", e),
+        }
+    }
+
+    // The editor widget is shared by all buffers, so it gets the newly active
+    // buffer's cursor and keeps focus if it had it
+    fn restore_editor_state(&mut self) {
+        self.get_current_buffer_mut().last_access = Utc::now().timestamp();
+        self.restore_buffer_cursor();
+        if self.editor_has_focus {
+            self.should_focus_text_edit = true;
+        }
+    }
+
+    // Saved positions can point past the end if the file shrank since
+    fn restore_buffer_cursor(&mut self) {
+        let cursor_pos = self.get_current_buffer_mut().clamp_cursor();
+        self.pending_cursor = Some(CCursorRange::one(CCursor::new(cursor_pos)));
+    }
+
+    fn set_cursor(&mut self, char_idx: usize) {
+        let buffer = self.get_current_buffer_mut();
+        buffer.cursor_pos = char_idx.min(buffer.content.chars().count());
+        let cursor_pos = buffer.cursor_pos;
+        self.pending_cursor = Some(CCursorRange::one(CCursor::new(cursor_pos)));
+        self.should_focus_text_edit = true;
+    }
+
+    // An edit made outside the editor undoes on its own, not together with the typing before it
+    fn record_undo_point(&mut self) {
+        let buffer = self.get_current_buffer_mut();
+        buffer.history.seed(&buffer.content, buffer.cursor_pos);
+        buffer.history.break_step();
+    }
+
+    fn normalize_current_buffer(&mut self) {
+        if self.get_current_buffer().locked {
+            return;
+        }
+
+        let normalized = normalize_whitespace(&self.get_current_buffer().content);
+        if normalized == self.get_current_buffer().content {
+            return;
+        }
+
+        self.record_undo_point();
+        let buffer = self.get_current_buffer_mut();
+        let cursor_pos = buffer.cursor_pos.min(normalized.chars().count());
+        buffer.content = normalized;
+        buffer.mark_edited();
+        self.set_cursor(cursor_pos);
+        self.save_state();
+    }
+
+    fn format_with_external_command(&mut self) {
+        if self.get_current_buffer().locked {
+            return;
+        }
+        if self.settings.formatter_command.trim().is_empty() {
+            self.console.push(ConsoleLine::note(
+                "set a formatter command in settings first".to_string(),
+            ));
+            return;
+        }
+
+        let content = self.get_current_buffer().content.clone();
+        let formatted = match run_formatter(
+            &self.settings.formatter_command,
+            &content,
+            FORMATTER_TIMEOUT,
+        ) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                log::error!("Formatter failed: {}", e);
+                self.console
+                    .push(ConsoleLine::note(format!("formatter failed: {}", e)));
+                return;
+            }
+        };
+        let (formatted, _) = normalize_loaded_text(&formatted);
+        if formatted == content {
+            return;
+        }
+
+        self.record_undo_point();
+        let buffer = self.get_current_buffer_mut();
+        let cursor_pos = buffer.cursor_pos;
+        buffer.content = formatted;
+        buffer.mark_edited();
+        self.set_cursor(cursor_pos);
+        self.save_state();
+    }
+
+    // Swaps the cursor's line with the one above, the cursor moves up with its line
+    fn transpose_line_up(&mut self) {
+        let buffer = self.get_current_buffer();
+        if buffer.locked {
+            return;
+        }
+
+        let line_index = buffer.line_index();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        let line = line_index.line_of_byte(cursor_byte);
+        if line == 0 {
+            return;
+        }
+
+        let above = line_index.line_range(&buffer.content, line - 1);
+        let current = line_index.line_range(&buffer.content, line);
+        let column = buffer.content[current.start..cursor_byte].chars().count();
+        let swapped = format!(
+            "{}{}\n{}{}",
+            &buffer.content[..above.start],
+            &buffer.content[current.clone()],
+            &buffer.content[above.clone()],
+            &buffer.content[current.end..]
+        );
+        let cursor_pos = buffer.content[..above.start].chars().count() + column;
+
+        self.record_undo_point();
+        let buffer = self.get_current_buffer_mut();
+        buffer.content = swapped;
+        buffer.mark_edited();
+        self.set_cursor(cursor_pos);
+        self.save_state();
+    }
+
+    // Every line the selection touches, or the cursor's line
+    fn toggle_comment(&mut self, selection: Option<(usize, usize)>) {
+        let buffer = self.get_current_buffer();
+        if buffer.locked {
+            return;
+        }
+
+        let selection = selection.unwrap_or((buffer.cursor_pos, buffer.cursor_pos));
+        let (new_content, cursor_pos) =
+            toggle_comments_in(&buffer.content, selection, buffer.cursor_pos);
+
+        self.record_undo_point();
+        let buffer = self.get_current_buffer_mut();
+        buffer.content = new_content;
+        buffer.mark_edited();
+        self.set_cursor(cursor_pos);
+        self.save_state();
+    }
+
+    fn insert_at_cursor(&mut self, text: &str) {
+        if self.get_current_buffer().locked {
+            return;
+        }
+
+        let buffer = self.get_current_buffer_mut();
+        let byte_idx = char_to_byte(&buffer.content, buffer.cursor_pos);
+        buffer.content.insert_str(byte_idx, text);
+        buffer.mark_edited();
+        let new_cursor_pos = buffer.cursor_pos + text.chars().count();
+
+        self.set_cursor(new_cursor_pos);
+        self.save_state();
+    }
+
+    fn insert_timestamp_comment(&mut self) {
+        if self.get_current_buffer().locked {
+            return;
+        }
+
+        let buffer = self.get_current_buffer();
+        let byte_idx = char_to_byte(&buffer.content, buffer.cursor_pos);
+        let at_line_start = byte_idx == 0 || buffer.content[..byte_idx].ends_with('\n');
+        // chrono reports a bad format string as a formatting error, which `format!` would panic on
+        let mut timestamp = String::new();
+        let now = Local::now();
+        if write!(timestamp, "{}", now.format(&self.settings.timestamp_format)).is_err() {
+            self.console.push(ConsoleLine::note(format!(
+                "invalid timestamp format: {}",
+                self.settings.timestamp_format
+            )));
+            return;
+        }
+        let comment = if at_line_start {
+            format!("; {}\n", timestamp)
+        } else {
+            format!("\n; {}\n", timestamp)
+        };
+
+        self.record_undo_point();
+        self.insert_at_cursor(&comment);
+    }
+
+    fn run_sapf(&mut self) {
+        self.connections = self
+            .settings
+            .connection_names()
+            .iter()
+            .filter_map(|name| self.spawn_connection(name))
+            .collect();
+        if self.connections.is_empty() {
+            self.console.push(ConsoleLine::note(
+                "no sapf is running, editing still works and evals wait until you reconnect"
+                    .to_string(),
+            ));
+        }
+        self.flush_pending_sends();
+    }
+
+    fn spawn_connection(&mut self, name: &str) -> Option<SapfConnection> {
+        let program = self.settings.sapf_program();
+        match SapfConnection::spawn(name, &program) {
+            Ok(connection) => Some(connection),
+            Err(e) => {
+                log::error!(
+                    "Failed to start sapf ({}, {}): {}",
+                    name,
+                    program.display(),
+                    e
+                );
+                self.console.push(ConsoleLine::note(format!(
+                    "couldn't start sapf \"{}\" ({}): {}, check the sapf binary in settings",
+                    name,
+                    program.display(),
+                    e
+                )));
+                None
+            }
+        }
+    }
+
+    fn flush_pending_sends(&mut self) {
+        if self.connections.is_empty() || self.pending_sends.is_empty() {
+            return;
+        }
+
+        let pending: Vec<String> = self.pending_sends.drain(..).collect();
+        self.console.push(ConsoleLine::note(format!(
+            "sapf is connected, sending {} held lines",
+            pending.len()
+        )));
+        for line in pending {
+            self.send_to_sapf(&line);
+        }
+    }
+
+    // Starts newly configured connections and drops removed ones, leaving the rest running
+    fn sync_connections(&mut self) {
+        let names = self.settings.connection_names();
+        let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.connections)
+            .into_iter()
+            .partition(|connection| names.contains(&connection.name));
+        self.connections = kept;
+        shut_down_connections(removed);
+        for name in &names {
+            if !self.connections.iter().any(|c| &c.name == name)
+                && let Some(connection) = self.spawn_connection(name)
+            {
+                self.connections.push(connection);
+            }
+        }
+        self.connections
+            .sort_by_key(|connection| names.iter().position(|name| name == &connection.name));
+        self.flush_pending_sends();
+    }
+
+    fn current_connection_idx(&self) -> usize {
+        self.get_current_buffer()
+            .connection_id
+            .as_ref()
+            .and_then(|name| self.connections.iter().position(|c| &c.name == name))
+            .unwrap_or(0)
+    }
+
+    fn set_buffer_connection(&mut self, idx: usize, name: String) {
+        let is_primary = self
+            .connections
+            .first()
+            .is_some_and(|primary| primary.name == name);
+        self.buffers[idx].connection_id = if is_primary { None } else { Some(name) };
+        self.save_state();
+    }
+
+    // Buffers pointing at a connection that's no longer configured go back to the primary
+    fn check_buffer_connections(&mut self) {
+        let names = self.settings.connection_names();
+        let mut reassigned = false;
+        for buffer in &mut self.buffers {
+            let Some(name) = buffer.connection_id.take_if(|name| !names.contains(name)) else {
+                continue;
+            };
+            self.console.push(ConsoleLine::note(format!(
+                "sapf engine \"{}\" for {} isn't available, using \"{}\"",
+                name, buffer.name, names[0]
+            )));
+            reassigned = true;
+        }
+
+        if reassigned {
+            self.save_state();
+        }
+    }
+
+    fn restart_sapf(&mut self) {
+        shut_down_connections(std::mem::take(&mut self.connections));
+        self.run_sapf();
+    }
+
+    // Raw command lines are sent between the code around them, in order
+    fn eval(&mut self, code: &str) {
+        let prefix = self.settings.raw_command_prefix.trim().to_string();
+        if prefix.is_empty() {
+            self.eval_code(code);
+            return;
+        }
+
+        let mut chunk: Vec<&str> = Vec::new();
+        for line in code.lines() {
+            match line.trim_start().strip_prefix(prefix.as_str()) {
+                Some(command) => {
+                    if !chunk.is_empty() {
+                        self.eval_code(&chunk.join("\n"));
+                        chunk.clear();
+                    }
+                    let command = command.trim();
+                    if !command.is_empty() {
+                        self.send_to_sapf(command);
+                    }
                 }
-            },
-            Some(ext) => {
-                println!(
-                    "Unsupported file type: .{} (only .sapf and .txt files are supported)",
-                    ext
+                None => chunk.push(line),
+            }
+        }
+        if !chunk.is_empty() {
+            self.eval_code(&chunk.join("\n"));
+        }
+    }
+
+    fn eval_code(&mut self, code: &str) {
+        let mut code = clean_eval_code(code);
+        if self.settings.trim_leading_whitespace {
+            code = code
+                .lines()
+                .map(str::trim_start)
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+        if let Err(e) = check_brackets(&code) {
+            self.console.push(ConsoleLine::note(format!(
+                "warning: {} in the evaluated code",
+                e
+            )));
+        }
+        if !code.trim().is_empty() {
+            if self.settings.safe_audition {
+                code = self.settings.wrap_for_audition(code.trim_end());
+            }
+            self.send_to_sapf(&code);
+        }
+    }
+
+    fn eval_from_buffer(&mut self, code: &str) {
+        let buffer = self.get_current_buffer_mut();
+        buffer.evaluated_lines.extend(
+            code.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+
+        let buffer = self.get_current_buffer();
+        let buffer_id = buffer.id;
+        let line_index = buffer.line_index();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        let cursor_line = line_index.line_of_byte(cursor_byte);
+
+        // Evaluated code is cut from around the cursor, find the line it starts on
+        let first_line = code.lines().find(|line| !line.trim().is_empty());
+        let start_line = first_line
+            .and_then(|first_line| {
+                (0..=cursor_line)
+                    .rev()
+                    .find(|&line| buffer.line_text(line).contains(first_line.trim()))
+            })
+            .unwrap_or(cursor_line);
+
+        self.last_eval = Some(code.to_string());
+        self.last_eval_origin = Some(EvalOrigin {
+            buffer_id,
+            start_line,
+            line_count: code.lines().count().max(1),
+        });
+        if self
+            .error_marker
+            .as_ref()
+            .is_some_and(|marker| marker.buffer_id == buffer_id)
+        {
+            self.error_marker = None;
+        }
+
+        self.eval(code);
+    }
+
+    fn mark_error(&mut self, message: &str) {
+        let Some(origin) = self.last_eval_origin.take() else {
+            return;
+        };
+        let Some(buffer) = self.buffers.iter().find(|b| b.id == origin.buffer_id) else {
+            return;
+        };
+
+        let mut lines = origin.start_line..origin.start_line + origin.line_count;
+        let line = error_subject(message)
+            .and_then(|subject| lines.find(|&line| buffer.line_text(line).contains(subject)))
+            .unwrap_or(origin.start_line);
+
+        self.error_marker = Some(ErrorMarker {
+            buffer_id: origin.buffer_id,
+            line,
+            line_text: buffer.line_text(line).to_string(),
+            message: message.trim().to_string(),
+        });
+    }
+
+    // Char range of the error line in the current buffer, dropping the marker
+    // once that line has been edited
+    fn current_error_range(&mut self) -> Option<(usize, usize)> {
+        let marker = self.error_marker.as_ref()?;
+        let buffer = self.get_current_buffer();
+        if marker.buffer_id != buffer.id {
+            return None;
+        }
+
+        let range = buffer.line_index().line_range(&buffer.content, marker.line);
+        if buffer.content[range.clone()] != marker.line_text {
+            self.error_marker = None;
+            return None;
+        }
+
+        let start = buffer.content[..range.start].chars().count();
+        let end = start + buffer.content[range].chars().count();
+        Some((start, end))
+    }
+
+    fn paint_error_squiggle(&mut self, ui: &mut Ui, galley: &egui::Galley, galley_pos: egui::Pos2) {
+        let Some((start, end)) = self.current_error_range() else {
+            return;
+        };
+
+        let offset = galley_pos.to_vec2();
+        let start_rect = galley
+            .pos_from_ccursor(CCursor::new(start))
+            .translate(offset);
+        let end_rect = galley.pos_from_ccursor(CCursor::new(end)).translate(offset);
+        // Wrapped lines are only underlined on their first row
+        let right = if end_rect.min.y > start_rect.min.y {
+            galley.rect.translate(offset).right()
+        } else {
+            end_rect.right()
+        };
+        let rect = egui::Rect::from_x_y_ranges(
+            start_rect.left()..=right.max(start_rect.left() + 8.0),
+            start_rect.y_range(),
+        );
+
+        let color = ui.visuals().error_fg_color;
+        let step = 3.0;
+        let y = rect.bottom();
+        let points: Vec<egui::Pos2> = (0..=((rect.width() / step) as usize))
+            .map(|i| {
+                let x = rect.left() + i as f32 * step;
+                let dy = if i % 2 == 0 { 0.0 } else { -step / 2.0 };
+                egui::pos2(x, y + dy)
+            })
+            .collect();
+        ui.painter()
+            .add(egui::Shape::line(points, egui::Stroke::new(1.0, color)));
+
+        if let Some(marker) = &self.error_marker {
+            ui.interact(rect, egui::Id::new("error_squiggle"), egui::Sense::hover())
+                .on_hover_text(marker.message.as_str());
+        }
+    }
+
+    fn schedule_live_eval(&mut self) {
+        if self.get_current_buffer().live {
+            let debounce = self.settings.live_debounce_ms.max(MIN_LIVE_DEBOUNCE_MS);
+            self.live_eval_due = Some(Instant::now() + Duration::from_millis(debounce));
+        }
+    }
+
+    fn process_live_eval(&mut self, ctx: &egui::Context) {
+        let Some(due) = self.live_eval_due else {
+            return;
+        };
+
+        let now = Instant::now();
+        if now < due {
+            ctx.request_repaint_after(due - now);
+            return;
+        }
+        self.live_eval_due = None;
+
+        // Re-sending the same code on every keystroke would just restart the sound
+        let code = clean_eval_code(&self.get_code_to_send());
+        if self.last_live_eval.as_ref() != Some(&code) {
+            self.eval_from_buffer(&code);
+            self.last_live_eval = Some(code);
+        }
+    }
+
+    fn send_to_sapf(&mut self, code: &str) {
+        if self.settings.dry_run {
+            for line in code.lines() {
+                self.console
+                    .push(ConsoleLine::note(format!("(dry) {}", line)));
+            }
+            return;
+        }
+
+        self.push_eval_history(code);
+
+        let idx = self.current_connection_idx();
+        let labelled = self.connections.len() > 1;
+        let Some(connection) = self
+            .connections
+            .get_mut(idx)
+            .filter(|connection| connection.is_alive())
+        else {
+            self.hold_while_disconnected(code);
+            return;
+        };
+        let source = labelled.then(|| connection.name.clone());
+        let source = source.as_deref();
+
+        for line in code.lines() {
+            self.console
+                .push(ConsoleLine::sent(line.to_string()).with_source(source));
+
+            if !connection.enqueue(line.to_string()) {
+                self.console.push(
+                    ConsoleLine::note("send queue is full, dropped the rest".to_string())
+                        .with_source(source),
                 );
+                break;
             }
-            None => {
-                println!("Dropped file has no extension (only .sapf and .txt files are supported)");
+        }
+    }
+
+    fn push_eval_history(&mut self, code: &str) {
+        let code = code.trim();
+        if code.is_empty() || self.eval_history.last().map(String::as_str) == Some(code) {
+            return;
+        }
+        self.eval_history.push(code.to_string());
+        if self.eval_history.len() > MAX_EVAL_HISTORY {
+            let excess = self.eval_history.len() - MAX_EVAL_HISTORY;
+            self.eval_history.drain(..excess);
+        }
+    }
+
+    fn hold_while_disconnected(&mut self, code: &str) {
+        log::info!("SAPF not connected, holding the code");
+        let room = MAX_PENDING_SENDS.saturating_sub(self.pending_sends.len());
+        let lines: Vec<&str> = code.lines().collect();
+        self.pending_sends
+            .extend(lines.iter().take(room).map(|line| line.to_string()));
+
+        let message = if lines.len() > room {
+            format!(
+                "sapf isn't connected and {} lines are already waiting, dropped this, reconnect from the status bar",
+                MAX_PENDING_SENDS
+            )
+        } else {
+            format!(
+                "sapf isn't connected, holding {} lines until it is (reconnect from the status bar)",
+                self.pending_sends.len()
+            )
+        };
+        self.console.push(ConsoleLine::note(message));
+    }
+
+    fn process_send_queue(&mut self, ctx: &egui::Context) {
+        let delay = Duration::from_millis(self.settings.send_line_delay_ms);
+
+        for connection in &mut self.connections {
+            if let Some(wait) = connection.process_queue(delay) {
+                ctx.request_repaint_after(wait);
             }
         }
     }
 
-    fn trigger_completions(&mut self) {
-        if let Some(current_word) = get_current_word_for_completion(
-            &self.get_current_buffer().content,
-            self.get_current_buffer().cursor_pos,
-        ) {
-            if !current_word.is_empty() {
-                self.completions = self.sapf_grammar.get_completions(&current_word);
-                self.show_completions = !self.completions.is_empty();
-            } else {
-                self.completions = self.sapf_grammar.get_completions("");
-                self.show_completions = !self.completions.is_empty();
-            }
-        } else {
-            self.completions = self.sapf_grammar.get_completions("");
-            self.show_completions = !self.completions.is_empty();
+    fn update_bracket_check(&mut self, ctx: &egui::Context) {
+        let buffer = self.get_current_buffer();
+        let key = (buffer.id, buffer.revision);
+        if self
+            .bracket_status
+            .as_ref()
+            .is_some_and(|(checked, _)| *checked == key)
+        {
+            return;
+        }
+
+        // Rechecking on every keystroke is wasteful for big buffers
+        let now = Instant::now();
+        if now < self.bracket_check_after {
+            ctx.request_repaint_after(self.bracket_check_after - now);
+            return;
+        }
+
+        let result = check_brackets(&buffer.content);
+        self.bracket_status = Some((key, result));
+        self.bracket_check_after = now + BRACKET_CHECK_INTERVAL;
+    }
+
+    fn update_minimap(&mut self, ctx: &egui::Context) {
+        if !self.settings.show_minimap {
+            return;
+        }
+
+        let buffer = self.get_current_buffer();
+        let key = (buffer.id, buffer.revision);
+        if self
+            .minimap_lines
+            .as_ref()
+            .is_some_and(|(computed, _)| *computed == key)
+        {
+            return;
+        }
+
+        let now = Instant::now();
+        if now < self.minimap_update_after {
+            ctx.request_repaint_after(self.minimap_update_after - now);
+            return;
+        }
+
+        let lines = line_overview(&buffer.content, |word| self.sapf_grammar.is_keyword(word));
+        self.minimap_lines = Some((key, lines));
+        self.minimap_update_after = now + MINIMAP_UPDATE_INTERVAL;
+    }
+
+    fn minimap_panel(&mut self, ui: &mut Ui) {
+        let Some((_, lines)) = &self.minimap_lines else {
+            return;
+        };
+
+        let (offset, content_height, view_height) = self.editor_viewport;
+        let viewport = if content_height > 0.0 {
+            (
+                offset / content_height,
+                ((offset + view_height) / content_height).min(1.0),
+            )
+        } else {
+            (0.0, 1.0)
+        };
+
+        let mut jump_to = None;
+        egui::SidePanel::right("minimap")
+            .exact_width(MINIMAP_WIDTH)
+            .resizable(false)
+            .show_separator_line(false)
+            .frame(egui::Frame::NONE)
+            .show_inside(ui, |ui| jump_to = minimap_ui(ui, lines, viewport));
+
+        if let Some(fraction) = jump_to {
+            self.minimap_jump = Some((fraction * content_height - view_height / 2.0).max(0.0));
+        }
+    }
+
+    fn current_bracket_error(&self) -> Option<BracketError> {
+        let buffer = self.get_current_buffer();
+        match &self.bracket_status {
+            Some((key, Err(e))) if *key == (buffer.id, buffer.revision) => Some(*e),
+            _ => None,
+        }
+    }
+
+    fn toggle_armed_at_cursor(&mut self) {
+        let buffer = self.get_current_buffer_mut();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        let line = buffer.line_index().line_of_byte(cursor_byte);
+        buffer.toggle_armed_line(line);
+        self.save_state();
+    }
+
+    fn fire_all_armed(&mut self) {
+        let lines: Vec<String> = self
+            .get_current_buffer()
+            .armed_lines
+            .iter()
+            .map(|armed| armed.text.clone())
+            .collect();
+        for line in lines {
+            self.send_to_sapf(&clean_eval_code(&line));
+        }
+    }
+
+    // Steps through the armed lines top to bottom, wrapping around
+    fn fire_next_armed(&mut self) {
+        let buffer = self.get_current_buffer_mut();
+        if buffer.armed_lines.is_empty() {
+            return;
+        }
+
+        let idx = buffer.next_armed % buffer.armed_lines.len();
+        buffer.next_armed = idx + 1;
+        let line = clean_eval_code(&buffer.armed_lines[idx].text);
+        self.send_to_sapf(&line);
+    }
+
+    fn paint_armed_lines(&self, ui: &Ui, galley: &egui::Galley, galley_pos: egui::Pos2) {
+        let buffer = self.get_current_buffer();
+        if buffer.armed_lines.is_empty() {
+            return;
+        }
+
+        let offset = galley_pos.to_vec2();
+        let x = galley_pos.x - TEXT_EDIT_MARGIN as f32 / 2.0;
+        let stroke = egui::Stroke::new(1.0, ui.visuals().selection.bg_fill);
+        let line_index = buffer.line_index();
+        for armed in &buffer.armed_lines {
+            let start = line_index.line_range(&buffer.content, armed.line).start;
+            let char_idx = buffer.content[..start].chars().count();
+            let row = galley
+                .pos_from_ccursor(CCursor::new(char_idx))
+                .translate(offset);
+            ui.painter()
+                .circle_stroke(egui::pos2(x, row.center().y), 4.0, stroke);
+        }
+    }
+
+    // Only lines in view are painted, big buffers stay cheap
+    fn paint_whitespace(&self, ui: &Ui, galley: &egui::Galley, galley_pos: egui::Pos2) {
+        if !self.settings.show_whitespace {
+            return;
+        }
+
+        let clip = ui.clip_rect();
+        let offset = galley_pos.to_vec2();
+        let color = ui.visuals().weak_text_color().gamma_multiply(0.6);
+        let font = egui::FontId::proportional(self.settings.font_size * 0.8);
+        let pos_of = |char_idx: usize| {
+            galley
+                .pos_from_ccursor(CCursor::new(char_idx))
+                .translate(offset)
+        };
+
+        let content = &self.get_current_buffer().content;
+        let mut char_idx = 0;
+        let mut lines = content.split('\n').peekable();
+        while let Some(line) = lines.next() {
+            let line_chars = line.chars().count();
+            if pos_of(char_idx).top() > clip.bottom() {
+                break;
+            }
+
+            let line_end = pos_of(char_idx + line_chars);
+            if line_end.bottom() >= clip.top() {
+                for (i, c) in line.chars().enumerate() {
+                    let glyph = match c {
+                        ' ' => "·",
+                        '\t' => "→",
+                        '\r' => "¤",
+                        _ => continue,
+                    };
+                    let at = pos_of(char_idx + i);
+                    let next = pos_of(char_idx + i + 1);
+                    let x = if next.top() == at.top() {
+                        (at.left() + next.left()) / 2.0
+                    } else {
+                        at.left() + 3.0
+                    };
+                    ui.painter().text(
+                        egui::pos2(x, at.center().y),
+                        egui::Align2::CENTER_CENTER,
+                        glyph,
+                        font.clone(),
+                        color,
+                    );
+                }
+                if lines.peek().is_some() {
+                    ui.painter().text(
+                        line_end.left_center() + egui::vec2(2.0, 0.0),
+                        egui::Align2::LEFT_CENTER,
+                        "¬",
+                        font.clone(),
+                        color,
+                    );
+                }
+            }
+            char_idx += line_chars + 1;
+        }
+    }
+
+    fn paint_evaluated_lines(&self, ui: &Ui, galley: &egui::Galley, galley_pos: egui::Pos2) {
+        let buffer = self.get_current_buffer();
+        if buffer.evaluated_lines.is_empty() {
+            return;
+        }
+
+        let offset = galley_pos.to_vec2();
+        let x = galley_pos.x - TEXT_EDIT_MARGIN as f32 / 2.0;
+        let color = ui.visuals().weak_text_color();
+        let mut char_idx = 0;
+        for line in buffer.content.split('\n') {
+            if buffer.evaluated_lines.contains(line.trim()) {
+                let row = galley
+                    .pos_from_ccursor(CCursor::new(char_idx))
+                    .translate(offset);
+                ui.painter()
+                    .circle_filled(egui::pos2(x, row.center().y), 2.0, color);
+            }
+            char_idx += line.chars().count() + 1;
+        }
+    }
+
+    fn paint_bracket_error(&self, ui: &Ui, galley: &egui::Galley, galley_pos: egui::Pos2) {
+        let Some(error) = self.current_bracket_error() else {
+            return;
+        };
+
+        let content = &self.get_current_buffer().content;
+        let char_idx = content[..error.position].chars().count();
+        let offset = galley_pos.to_vec2();
+        let start = galley.pos_from_ccursor(CCursor::new(char_idx));
+        let end = galley.pos_from_ccursor(CCursor::new(char_idx + 1));
+        let rect = egui::Rect::from_x_y_ranges(start.left()..=end.left(), start.y_range())
+            .translate(offset)
+            .expand(1.0);
+        ui.painter().rect_stroke(
+            rect,
+            2.0,
+            egui::Stroke::new(1.0, ui.visuals().error_fg_color),
+            egui::StrokeKind::Outside,
+        );
+    }
+
+    fn status_bar_ui(&mut self, ui: &mut Ui) {
+        let labelled = self.connections.len() > 1;
+        let mut reconnect = false;
+        let mut show_console = false;
+        ui.horizontal(|ui| {
+            if let Some(batch) = &self.file_batch {
+                ui.spinner();
+                ui.weak(format!(
+                    "opening {}/{}: {}",
+                    batch.done, batch.total, batch.current
+                ));
+            }
+            if self.settings.console_collapsed {
+                let unseen = self.console.len().saturating_sub(self.console_seen_len);
+                let label = if unseen > 0 {
+                    format!("console ({} new)", unseen)
+                } else {
+                    "console".to_string()
+                };
+                show_console = ui
+                    .small_button(label)
+                    .on_hover_text(self.keymap.label("toggle_console"))
+                    .clicked();
+            }
+            if self.connections.is_empty() || !self.sapf_alive {
+                ui.colored_label(ui.visuals().error_fg_color, "sapf not connected");
+                if !self.pending_sends.is_empty() {
+                    ui.weak(format!("{} lines waiting", self.pending_sends.len()));
+                }
+                reconnect = ui.small_button("reconnect").clicked();
+            }
+            if self.ephemeral {
+                ui.colored_label(ui.visuals().warn_fg_color, "ephemeral, nothing is saved")
+                    .on_hover_text("Buffers and settings won't be written to disk this session");
+            }
+            if let Some(error) = self.current_bracket_error() {
+                ui.colored_label(ui.visuals().error_fg_color, error.to_string());
+            }
+            if self.settings.dry_run {
+                ui.weak("dry run");
+            }
+            if self.settings.safe_audition {
+                ui.colored_label(ui.visuals().warn_fg_color, "safe audition")
+                    .on_hover_text(format!(
+                        "Evaluated code is sent as \"{}\"",
+                        self.settings.audition_template
+                    ));
+            }
+            for connection in &self.connections {
+                if let Some((sent, total)) = connection.queue_progress() {
+                    if labelled {
+                        ui.weak(format!("{}: sending {}/{}", connection.name, sent, total));
+                    } else {
+                        ui.weak(format!("sending {}/{}", sent, total));
+                    }
+                }
+            }
+        });
+
+        if reconnect {
+            self.restart_sapf();
+        }
+        if show_console {
+            self.toggle_console();
+        }
+    }
+
+    // Drag handle along the top of the console, double click resets the height
+    fn console_divider_ui(&mut self, ui: &mut Ui) {
+        let top = ui.max_rect().top();
+        let divider = egui::Rect::from_x_y_ranges(ui.max_rect().x_range(), top - 3.0..=top + 3.0);
+        let response = ui
+            .interact(
+                divider,
+                egui::Id::new("console_divider"),
+                egui::Sense::click_and_drag(),
+            )
+            .on_hover_cursor(egui::CursorIcon::ResizeVertical);
+
+        let max_height =
+            (ui.ctx().screen_rect().height() - MIN_EDITOR_HEIGHT).max(MIN_CONSOLE_HEIGHT);
+        if response.dragged() {
+            self.settings.console_height = (self.settings.console_height - response.drag_delta().y)
+                .clamp(MIN_CONSOLE_HEIGHT, max_height);
+        }
+        if response.double_clicked() {
+            self.settings.console_height = DEFAULT_CONSOLE_HEIGHT;
+        }
+        if response.drag_stopped() || response.double_clicked() {
+            self.save_state();
+        }
+    }
+
+    fn console_ui(&mut self, ui: &mut Ui, show_sent: bool) {
+        if !self.sapf_alive {
+            let mut restart = false;
+            ui.horizontal(|ui| {
+                ui.colored_label(ui.visuals().error_fg_color, "sapf disconnected");
+                restart = ui.small_button("restart").clicked();
+            });
+            if restart {
+                self.restart_sapf();
+            }
+        }
+
+        // Registered before the lines so they keep their own hover and selection
+        let console_id = egui::Id::new(CONSOLE_ID);
+        let region = ui.interact(
+            ui.available_rect_before_wrap(),
+            console_id,
+            egui::Sense::click(),
+        );
+        if region.clicked() || self.should_focus_console {
+            region.request_focus();
+            self.should_focus_console = false;
+        }
+
+        let focused = region.has_focus();
+        let mut scroll_delta = 0.0;
+        let mut jump_to_top = false;
+        if focused {
+            ui.memory_mut(|m| {
+                m.set_focus_lock_filter(
+                    console_id,
+                    egui::EventFilter {
+                        vertical_arrows: true,
+                        ..Default::default()
+                    },
+                )
+            });
+            let line_height = ui.text_style_height(&egui::TextStyle::Body);
+            let page_height = ui.available_height() - line_height;
+            ui.input(|i| {
+                if i.key_pressed(Key::ArrowUp) {
+                    scroll_delta += line_height;
+                }
+                if i.key_pressed(Key::ArrowDown) {
+                    scroll_delta -= line_height;
+                }
+                if i.key_pressed(Key::PageUp) {
+                    scroll_delta += page_height;
+                }
+                if i.key_pressed(Key::PageDown) {
+                    scroll_delta -= page_height;
+                }
+                jump_to_top = i.key_pressed(Key::Home);
+                if i.key_pressed(Key::End) {
+                    self.console_jump_to_bottom = true;
+                }
+                if i.key_pressed(Key::Escape) {
+                    self.should_focus_text_edit = true;
+                }
+            });
+        }
+
+        let mut scroll_area = egui::ScrollArea::vertical().stick_to_bottom(self.console_follow);
+        if self.console_jump_to_bottom {
+            scroll_area = scroll_area.vertical_scroll_offset(f32::MAX);
+            self.console_jump_to_bottom = false;
+        } else if jump_to_top {
+            scroll_area = scroll_area.vertical_scroll_offset(0.0);
+        }
+
+        let output = scroll_area.show(ui, |ui| {
+            if scroll_delta != 0.0 {
+                ui.scroll_with_delta(egui::vec2(0.0, scroll_delta));
+            }
+            egui::Frame::new()
+                .inner_margin(Margin::same(TEXT_EDIT_MARGIN))
+                .show(ui, |ui| {
+                    ui.set_min_width(ui.available_width());
+                    let note_color = self
+                        .theme
+                        .colors(ui.visuals().dark_mode)
+                        .info
+                        .unwrap_or(ui.visuals().weak_text_color());
+                    for line in self
+                        .console
+                        .iter()
+                        .filter(|line| show_sent || line.kind != LineKind::Sent)
+                    {
+                        ui.label(line.rich_text(ui, note_color));
+                    }
+                });
+        });
+
+        if focused {
+            ui.painter().rect_stroke(
+                output.inner_rect,
+                2.0,
+                egui::Stroke::new(1.0, ui.visuals().selection.bg_fill),
+                egui::StrokeKind::Inside,
+            );
+        }
+
+        if !self.console.is_empty() {
+            let clear_rect = egui::Rect::from_min_size(
+                output.inner_rect.right_top() + egui::vec2(-50.0, 4.0),
+                egui::vec2(42.0, 18.0),
+            );
+            if ui
+                .put(clear_rect, egui::Button::new("clear").small())
+                .on_hover_text(self.keymap.label("clear_console"))
+                .clicked()
+            {
+                self.clear_console();
+            }
+        }
+
+        let max_offset = (output.content_size.y - output.inner_rect.height()).max(0.0);
+        self.console_follow = output.state.offset.y >= max_offset - 2.0;
+        if self.console_follow {
+            self.console_seen_len = self.console.len();
+        } else if self.console.len() > self.console_seen_len {
+            let button_rect = egui::Rect::from_min_size(
+                output.inner_rect.right_bottom() - egui::vec2(110.0, 26.0),
+                egui::vec2(100.0, 20.0),
+            );
+            if ui
+                .put(button_rect, egui::Button::new("new output ↓"))
+                .clicked()
+            {
+                self.console_jump_to_bottom = true;
+            }
+        }
+    }
+
+    fn clear_console(&mut self) {
+        self.console.clear();
+        self.console_seen_len = 0;
+        self.console_follow = true;
+    }
+
+    // Drops the oldest lines, the unseen count stays relative to what's left
+    fn cap_console(&mut self) {
+        let max = self.settings.max_console_lines;
+        if max == 0 || self.console.len() <= max {
+            return;
+        }
+        let excess = self.console.len() - max;
+        self.console.drain(..excess);
+        self.console_seen_len = self.console_seen_len.saturating_sub(excess);
+    }
+
+    fn update_output(&mut self) {
+        let labelled = self.connections.len() > 1;
+        let mut errors = Vec::new();
+        for connection in &self.connections {
+            let source = labelled.then_some(connection.name.as_str());
+            for line in connection.drain_output() {
+                if !line.text.trim().is_empty() {
+                    if line.is_error() {
+                        errors.push(line.text.clone());
+                    }
+                    let line = line.with_source(source);
+                    match self.console.last_mut() {
+                        Some(last)
+                            if self.settings.collapse_repeated_output
+                                && last.kind == LineKind::Output
+                                && last.is_repeat_of(&line) =>
+                        {
+                            last.repeat += 1;
+                        }
+                        _ => self.console.push(line),
+                    }
+                }
+            }
+        }
+
+        for error in errors {
+            self.mark_error(&error);
+        }
+
+        let alive = self.connections.iter().all(SapfConnection::is_alive);
+        if self.sapf_alive && !alive {
+            for connection in self.connections.iter().filter(|c| !c.is_alive()) {
+                log::error!("sapf ({}) exited", connection.name);
+                self.console.push(ConsoleLine::note(format!(
+                    "sapf \"{}\" exited, restart it from the console",
+                    connection.name
+                )));
+            }
+        }
+        self.sapf_alive = alive;
+        self.cap_console();
+    }
+
+    // The cursor is a char index, a cursor right after a newline is on the next line
+    fn get_current_line(&self) -> String {
+        let buffer = self.get_current_buffer();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        let line = buffer.line_index().line_of_byte(cursor_byte);
+        buffer.line_text(line).trim_end_matches('\r').to_string()
+    }
+
+    // TODO Convoluted, need to fix edge cases
+    fn get_block_at_cursor(&self) -> Option<String> {
+        let content = &self.get_current_buffer().content;
+        let cursor_pos = self.get_current_buffer().cursor_pos;
+
+        if cursor_pos > content.len() {
+            return None;
+        }
+
+        if let Some((start, end)) = self.find_innermost_block(content, cursor_pos) {
+            let statement = self.extend_to_complete_statement(content, start, end);
+            return Some(statement.trim().to_string());
+        }
+
+        None
+    }
+
+    fn find_innermost_block(&self, content: &str, cursor_pos: usize) -> Option<(usize, usize)> {
+        let chars: Vec<char> = content.chars().collect();
+        let mut best_block: Option<(usize, usize)> = None;
+        let mut stack = Vec::new();
+
+        let mut i = 0;
+        while i < chars.len() {
+            let ch = chars[i];
+
+            if ch == '\\' && i + 1 < chars.len() && chars[i + 1] == '[' {
+                stack.push((i, ']'));
+                i += 2;
+                continue;
+            }
+
+            match ch {
+                '(' => {
+                    stack.push((i, ')'));
+                }
+                '{' => {
+                    stack.push((i, '}'));
+                }
+                '[' => {
+                    stack.push((i, ']'));
+                }
+                ')' | '}' | ']' => {
+                    if let Some((start, expected_close)) = stack.pop()
+                        && ch == expected_close
+                        && start <= cursor_pos
+                        && cursor_pos <= i
+                        && (best_block.is_none()
+                            || (i - start) < (best_block.unwrap().1 - best_block.unwrap().0))
+                    {
+                        best_block = Some((start, i));
+                    }
+                }
+                _ => {}
+            }
+
+            i += 1;
+        }
+
+        best_block
+    }
+
+    fn extend_to_complete_statement(
+        &self,
+        content: &str,
+        block_start: usize,
+        block_end: usize,
+    ) -> String {
+        let chars: Vec<char> = content.chars().collect();
+
+        let mut start = block_start;
+        for i in (0..block_start).rev() {
+            match chars[i] {
+                '\n' => {
+                    let line_has_content = (i + 1..block_start).any(|j| !chars[j].is_whitespace());
+                    if line_has_content {
+                        start = i + 1;
+                    }
+                    break;
+                }
+                ';' | ')' | '}' | ']' => {
+                    start = i + 1;
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        let mut end = block_end;
+        let mut i = block_end + 1;
+        while i < chars.len() {
+            match chars[i] {
+                ' ' | '\t' => {
+                    i += 1;
+                    continue;
+                }
+                '\n' | ';' => break,
+                '(' | '{' | '[' if i > block_end + 1 => break,
+                c if !c.is_whitespace() => end = i,
+                _ => {}
+            }
+            i += 1;
+        }
+
+        let statement: String = chars[start..=end].iter().collect();
+        statement.trim().to_string()
+    }
+
+    fn eval_clipboard(&mut self, text: &str) {
+        if text.trim().is_empty() {
+            self.console
+                .push(ConsoleLine::note("the clipboard is empty".to_string()));
+            return;
+        }
+        if text.len() > MAX_CLIPBOARD_EVAL_LEN {
+            self.console.push(ConsoleLine::note(format!(
+                "the clipboard holds {} KB, too much to send blindly, paste it into a buffer instead",
+                text.len() / 1024
+            )));
+            return;
+        }
+
+        if self.settings.keep_clipboard_evals {
+            self.append_to_clipboard_buffer(text);
+        }
+        self.eval(text);
+    }
+
+    // Adds to the clipboard buffer without switching to it
+    fn append_to_clipboard_buffer(&mut self, text: &str) {
+        let idx = match self
+            .buffers
+            .iter()
+            .position(|b| b.name == CLIPBOARD_BUFFER_NAME && b.file_path.is_none())
+        {
+            Some(idx) => idx,
+            None => {
+                let mut buffer = Buffer::new(CLIPBOARD_BUFFER_NAME.to_string());
+                buffer.id = self.next_buffer_uid();
+                self.buffers.push(buffer);
+                self.buffers.len() - 1
+            }
+        };
+
+        let buffer = &mut self.buffers[idx];
+        if !buffer.content.is_empty() && !buffer.content.ends_with('\n') {
+            buffer.content.push('\n');
+        }
+        buffer.content.push_str(text.trim_end());
+        buffer.content.push('\n');
+        buffer.mark_edited();
+        self.save_state();
+    }
+
+    // F6: editor -> console -> editor, the command input counts while it's open
+    fn cycle_focus(&mut self, ctx: &egui::Context) {
+        if self.show_scratch {
+            self.show_scratch = false;
+            self.scratch_input.clear();
+            self.should_focus_text_edit = true;
+            return;
+        }
+
+        let console_focused = ctx.memory(|m| m.has_focus(egui::Id::new(CONSOLE_ID)));
+        if console_focused {
+            self.should_focus_text_edit = true;
+        } else {
+            if self.settings.console_collapsed {
+                self.toggle_console();
+            }
+            self.should_focus_console = true;
+        }
+    }
+
+    fn toggle_console(&mut self) {
+        self.settings.console_collapsed = !self.settings.console_collapsed;
+        self.save_state();
+    }
+
+    // Every line of the buffer in order, blank and comment-only lines left out
+    fn send_buffer_to_sapf(&mut self) {
+        let buffer = self.get_current_buffer();
+        let name = buffer.name.clone();
+        let code = buffer
+            .content
+            .lines()
+            .filter(|line| {
+                let line = line.trim();
+                !line.is_empty() && !line.starts_with(';')
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if code.is_empty() {
+            return;
+        }
+
+        let line_count = code.lines().count();
+        log::info!("Sending buffer '{}' ({} lines)", name, line_count);
+        self.console.push(ConsoleLine::note(format!(
+            "sending {} ({} lines)",
+            name, line_count
+        )));
+        self.eval(&code);
+    }
+
+    fn send_stop(&mut self) {
+        let stop_command = self.settings.stop_command.clone();
+        self.send_to_sapf(&stop_command);
+    }
+
+    fn send_word_at_cursor(&mut self) {
+        let buffer = self.get_current_buffer();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        if let Some((word, _, _)) = get_word_at_cursor(&buffer.content, cursor_byte) {
+            self.send_to_sapf(&word);
+        }
+    }
+
+    fn jump_to_definition(&mut self) {
+        let buffer = self.get_current_buffer();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        let Some((word, _, _)) = get_word_at_cursor(&buffer.content, cursor_byte) else {
+            return;
+        };
+
+        // The current buffer's definitions come first
+        let mut order: Vec<usize> = (0..self.buffers.len()).collect();
+        order.sort_by_key(|&idx| idx != self.current_buffer_idx);
+        let sites: Vec<DefinitionSite> = order
+            .into_iter()
+            .flat_map(|idx| {
+                let buffer = &self.buffers[idx];
+                let line_index = buffer.line_index();
+                find_definitions(&buffer.content, &word)
+                    .into_iter()
+                    .map(move |byte_idx| {
+                        let line = line_index.line_of_byte(byte_idx);
+                        DefinitionSite {
+                            buffer_id: buffer.id,
+                            byte_idx,
+                            line,
+                            line_text: buffer.line_text(line).trim().to_string(),
+                        }
+                    })
+            })
+            .collect();
+
+        match sites.len() {
+            0 => self
+                .console
+                .push(ConsoleLine::note(format!("no definition of \"{}\"", word))),
+            1 => self.go_to_definition(&sites[0]),
+            _ => self.definition_choices = sites,
+        }
+    }
+
+    fn go_to_definition(&mut self, site: &DefinitionSite) {
+        let Some(idx) = self.buffers.iter().position(|b| b.id == site.buffer_id) else {
+            return;
+        };
+        if idx != self.current_buffer_idx {
+            self.switch_to_buffer(idx);
+        }
+
+        let content = &self.get_current_buffer().content;
+        let char_idx = content[..site.byte_idx.min(content.len())].chars().count();
+        self.set_cursor(char_idx);
+    }
+
+    fn show_scratch_input(&mut self, ctx: &egui::Context) {
+        if !self.show_scratch {
+            return;
+        }
+
+        let mut send = false;
+        let mut close = false;
+        egui::Area::new(egui::Id::new("scratch_send"))
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 40.0))
+            .order(egui::Order::Foreground)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let response = ui.add(
+                        egui::TextEdit::singleline(&mut self.scratch_input)
+                            .desired_width(360.0)
+                            .hint_text("send to sapf, Enter sends, Escape cancels"),
+                    );
+                    if self.should_focus_scratch {
+                        response.request_focus();
+                        self.should_focus_scratch = false;
+                    }
+                    if response.lost_focus() {
+                        if ui.input(|i| i.key_pressed(Key::Enter)) {
+                            send = true;
+                        } else {
+                            close = true;
+                        }
+                    }
+                });
+            });
+
+        if send {
+            let code = self.scratch_input.trim().to_string();
+            if !code.is_empty() {
+                self.send_to_sapf(&code);
+            }
+        }
+        if send || close {
+            self.show_scratch = false;
+            self.scratch_input.clear();
+            self.should_focus_text_edit = true;
+        }
+    }
+
+    fn show_goto_line_input(&mut self, ctx: &egui::Context) {
+        if !self.show_goto_line {
+            return;
+        }
+
+        let line_count = self.get_current_buffer().line_index().line_count();
+        let mut go = false;
+        let mut close = false;
+        egui::Area::new(egui::Id::new("goto_line"))
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 40.0))
+            .order(egui::Order::Foreground)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let response = ui.add(
+                        egui::TextEdit::singleline(&mut self.goto_line_input)
+                            .desired_width(160.0)
+                            .hint_text(format!("line 1-{}", line_count)),
+                    );
+                    if self.should_focus_goto_line {
+                        response.request_focus();
+                        self.should_focus_goto_line = false;
+                    }
+                    if response.lost_focus() {
+                        if ui.input(|i| i.key_pressed(Key::Enter)) {
+                            go = true;
+                        } else {
+                            close = true;
+                        }
+                    }
+                });
+            });
+
+        if go {
+            // Anything that isn't a number just closes the input
+            if let Ok(line) = self.goto_line_input.trim().parse::<usize>() {
+                self.go_to_line(line.saturating_sub(1));
+            }
+        }
+        if go || close {
+            self.show_goto_line = false;
+            self.goto_line_input.clear();
+            self.should_focus_text_edit = true;
+        }
+    }
+
+    fn show_log_window(&mut self, ctx: &egui::Context) {
+        if !self.show_log {
+            return;
+        }
+
+        let mut open = self.show_log;
+        egui::Window::new("log")
+            .open(&mut open)
+            .collapsible(false)
+            .default_size([480.0, 300.0])
+            .show(ctx, |ui| {
+                egui::ScrollArea::both()
+                    .stick_to_bottom(true)
+                    .auto_shrink([false, false])
+                    .show(ui, |ui| {
+                        for line in log::recent_lines() {
+                            ui.monospace(line);
+                        }
+                    });
+            });
+        self.show_log = open;
+    }
+
+    fn show_definitions_window(&mut self, ctx: &egui::Context) {
+        if self.definition_choices.is_empty() {
+            return;
+        }
+
+        let mut chosen = None;
+        let mut open = true;
+        egui::Window::new("definitions")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                for (idx, site) in self.definition_choices.iter().enumerate() {
+                    let buffer_name = self
+                        .buffers
+                        .iter()
+                        .find(|b| b.id == site.buffer_id)
+                        .map_or("?", |b| b.name.as_str());
+                    let label = format!("{}:{}  {}", buffer_name, site.line + 1, site.line_text);
+                    if ui.selectable_label(false, label).clicked() {
+                        chosen = Some(idx);
+                    }
+                }
+            });
+
+        if let Some(idx) = chosen {
+            let site = self.definition_choices.swap_remove(idx);
+            self.go_to_definition(&site);
+            self.definition_choices.clear();
+        } else if !open {
+            self.definition_choices.clear();
+        }
+    }
+
+    fn select_chars(&mut self, start: usize, end: usize) {
+        self.get_current_buffer_mut().cursor_pos = end;
+        self.pending_cursor = Some(CCursorRange::two(CCursor::new(start), CCursor::new(end)));
+        self.should_focus_text_edit = true;
+    }
+
+    // Selects by sapf's word boundaries rather than egui's, `.` and operators included
+    fn select_word_at(&mut self, char_idx: usize) {
+        let content = &self.get_current_buffer().content;
+        let Some(range) = word_at(content, char_to_byte(content, char_idx)) else {
+            return;
+        };
+        let start = content[..range.start].chars().count();
+        let end = start + content[range].chars().count();
+        self.select_chars(start, end);
+    }
+
+    // The run of non-blank lines around the cursor
+    fn cursor_line(&self) -> usize {
+        let buffer = self.get_current_buffer();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        buffer.line_index().line_of_byte(cursor_byte)
+    }
+
+    // First and last line of the run of non-blank lines around `line`
+    fn block_lines_around(&self, line: usize) -> Option<(usize, usize)> {
+        let buffer = self.get_current_buffer();
+        let is_blank = |line: usize| buffer.line_text(line).trim().is_empty();
+        if is_blank(line) {
+            return None;
+        }
+
+        let first = (0..line)
+            .rev()
+            .take_while(|&line| !is_blank(line))
+            .last()
+            .unwrap_or(line);
+        let last = (line + 1..buffer.line_index().line_count())
+            .take_while(|&line| !is_blank(line))
+            .last()
+            .unwrap_or(line);
+        Some((first, last))
+    }
+
+    // The paragraph around the cursor, or the one after it when the cursor is
+    // on a blank line between two
+    fn get_current_block(&self) -> Option<String> {
+        let buffer = self.get_current_buffer();
+        let line_count = buffer.line_index().line_count();
+        let start_line = (self.cursor_line()..line_count)
+            .find(|&line| !buffer.line_text(line).trim().is_empty())?;
+        let (first, last) = self.block_lines_around(start_line)?;
+
+        let block = (first..=last)
+            .map(|line| buffer.line_text(line).trim_end_matches('\r'))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Some(block)
+    }
+
+    fn select_block_at_cursor(&mut self) {
+        let Some((first, last)) = self.block_lines_around(self.cursor_line()) else {
+            return;
+        };
+
+        let buffer = self.get_current_buffer();
+        let line_index = buffer.line_index();
+        let start_byte = line_index.line_range(&buffer.content, first).start;
+        let end_byte = line_index.line_range(&buffer.content, last).end;
+        let start = buffer.content[..start_byte].chars().count();
+        let end = start + buffer.content[start_byte..end_byte].chars().count();
+        self.select_chars(start, end);
+    }
+
+    fn current_regions(&self) -> Vec<Region> {
+        find_regions(
+            &self.get_current_buffer().content,
+            &self.settings.region_start,
+            &self.settings.region_end,
+        )
+    }
+
+    fn eval_region_at_cursor(&mut self) {
+        let buffer = self.get_current_buffer();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        let cursor_line = buffer.line_index().line_of_byte(cursor_byte);
+
+        match self
+            .current_regions()
+            .into_iter()
+            .find(|region| region.contains_line(cursor_line))
+        {
+            Some(region) => {
+                let code = region.body(&self.get_current_buffer().content);
+                self.eval_from_buffer(&code);
+            }
+            None => self.console.push(ConsoleLine::note(
+                "the cursor isn't inside a region".to_string(),
+            )),
+        }
+    }
+
+    fn go_to_line(&mut self, line: usize) {
+        let buffer = self.get_current_buffer();
+        let line = line.min(buffer.line_index().line_count() - 1);
+        let start = buffer.line_index().line_range(&buffer.content, line).start;
+        let char_idx = buffer.content[..start].chars().count();
+        self.set_cursor(char_idx);
+    }
+
+    // Char range selected in the editor, if it has focus and the selection isn't empty
+    fn selection_range(&self, ctx: &egui::Context) -> Option<(usize, usize)> {
+        if !self.editor_has_focus {
+            return None;
+        }
+
+        let state = egui::TextEdit::load_state(ctx, egui::Id::new(EDITOR_ID))?;
+        let range = state.cursor.char_range()?;
+        let start = range.primary.index.min(range.secondary.index);
+        let end = range.primary.index.max(range.secondary.index);
+        (start != end).then_some((start, end))
+    }
+
+    fn selected_text(&self, ctx: &egui::Context) -> Option<String> {
+        let (start, end) = self.selection_range(ctx)?;
+        let content = &self.get_current_buffer().content;
+        let selected = &content[char_to_byte(content, start)..char_to_byte(content, end)];
+        Some(selected.to_string())
+    }
+
+    fn get_code_to_send(&self) -> String {
+        if let Some(block) = self.get_block_at_cursor() {
+            return block;
+        }
+
+        self.get_current_line()
+    }
+
+    fn handle_key_input(&mut self, ctx: &egui::Context) {
+        // egui turns Ctrl+Shift+V into a paste too, take it before the editor does
+        let clipboard = ctx.input_mut(|i| {
+            if !(i.modifiers.command && i.modifiers.shift) {
+                return None;
+            }
+            let idx = i
+                .events
+                .iter()
+                .position(|event| matches!(event, egui::Event::Paste(_)))?;
+            match i.events.remove(idx) {
+                egui::Event::Paste(text) => Some(text),
+                _ => None,
+            }
+        });
+        if let Some(text) = clipboard {
+            self.eval_clipboard(&text);
+        }
+        self.handle_completion_keys(ctx);
+        self.handle_snippet_keys(ctx);
+        self.handle_history_keys(ctx);
+        self.handle_undo_keys(ctx);
+
+        let mut toggle_always_on_top = false;
+        let mut normalize = false;
+        let mut transpose = false;
+        let mut toggle_comment = false;
+        let mut format = false;
+        let mut zoom = 1.0;
+        let mut insert_timestamp = false;
+        let mut toggle_theme = false;
+        let mut cycle_focus = false;
+        // Read before `ctx.input`, loading the editor state needs the context too
+        let selection = self.selected_text(ctx);
+        let selection_range = self.selection_range(ctx);
+
+        ctx.input(|i| {
+            cycle_focus = self.keymap.pressed(i, "cycle_focus");
+
+            if self.keymap.pressed(i, "eval_again")
+                && let Some(code) = self.last_eval.clone()
+            {
+                self.eval(&code);
+            }
+
+            if self.keymap.pressed(i, "eval_paragraph")
+                && let Some(code) = self.get_current_block()
+            {
+                self.eval_from_buffer(&code);
+            }
+
+            if self.keymap.pressed(i, "eval") {
+                let code = selection.clone().unwrap_or_else(|| self.get_code_to_send());
+                if self.settings.stop_before_eval {
+                    self.send_stop();
+                }
+                self.eval_from_buffer(&code);
+            }
+
+            if self.keymap.pressed(i, "send_word") {
+                self.send_word_at_cursor();
+            }
+
+            if self.keymap.pressed(i, "eval_loop") {
+                let code = clean_eval_code(&self.get_current_line());
+                if !code.trim().is_empty() {
+                    let wrapped = self.settings.wrap_in_loop(code.trim());
+                    self.eval_from_buffer(&wrapped);
+                }
+            }
+
+            if self.keymap.pressed(i, "stop") {
+                self.send_stop();
+            }
+
+            if self.keymap.pressed(i, "eval_line") {
+                self.send_stop();
+                let code = self.get_current_line();
+                self.eval_from_buffer(&code);
+            }
+
+            if self.keymap.pressed(i, "clear_stack") {
+                self.send_to_sapf("clear");
+            }
+
+            if self.keymap.pressed(i, "print_stack") {
+                self.send_to_sapf("prstk");
+            }
+
+            if self.keymap.pressed(i, "eval_region") {
+                self.eval_region_at_cursor();
+            }
+
+            if self.keymap.pressed(i, "toggle_console") {
+                self.toggle_console();
+            }
+
+            if self.keymap.pressed(i, "clear_console") {
+                self.clear_console();
+            }
+
+            if self.keymap.pressed(i, "scratch") {
+                self.show_scratch = true;
+                self.should_focus_scratch = true;
+            }
+
+            if self.keymap.pressed(i, "go_to_line") {
+                self.show_goto_line = true;
+                self.should_focus_goto_line = true;
+            }
+
+            if self.keymap.pressed(i, "arm_line") {
+                self.toggle_armed_at_cursor();
+            }
+
+            if self.keymap.pressed(i, "fire_armed") {
+                self.fire_all_armed();
+            }
+
+            if self.keymap.pressed(i, "fire_next_armed") {
+                self.fire_next_armed();
+            }
+
+            if self.keymap.pressed(i, "go_to_definition") {
+                self.jump_to_definition();
+            }
+
+            if self.keymap.pressed(i, "regions") {
+                self.show_regions = !self.show_regions;
+            }
+
+            if self.keymap.pressed(i, "record") {
+                let code = clean_eval_code(&self.get_current_line());
+                let date = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+                let buffer_name = &self.buffers[self.current_buffer_idx].name;
+                let file_name = format!("{}-{}", buffer_name, date);
+                let combined = format!("{} \"{}\" record", code, file_name);
+                self.eval(&combined);
+            }
+
+            if self.keymap.pressed(i, "completions") {
+                self.trigger_completions();
+                self.add_literal_completions();
+                self.selected_completion = 0;
+                self.scroll_to_completion = true;
+                self.completions_key = Some(self.editor_key());
+            }
+
+            if self.keymap.pressed(i, "insert_timestamp") {
+                insert_timestamp = true;
+            }
+
+            if self.keymap.pressed(i, "new_buffer") {
+                self.create_new_buffer();
+            }
+
+            if self.keymap.pressed(i, "rename_buffer") {
+                self.edit_buffer_name(self.current_buffer_idx);
+            }
+
+            if self.keymap.pressed(i, "close_buffer") {
+                self.request_close_buffer(self.current_buffer_idx);
+            }
+
+            if self.keymap.pressed(i, "save") {
+                self.save_buffer(self.current_buffer_idx);
+            }
+
+            if self.keymap.pressed(i, "save_as") {
+                self.export_buffer(self.current_buffer_idx);
+            }
+
+            if self.keymap.pressed(i, "open") {
+                self.load_file_into_new_buffer();
+            }
+
+            if self.keymap.pressed(i, "new_session") {
+                self.request_new_session();
+            }
+
+            if self.keymap.pressed(i, "docs_search") {
+                self.show_docs_search = true;
+                self.should_focus_docs_search = true;
+            }
+
+            if self.keymap.pressed(i, "search") {
+                self.show_search = true;
+                self.should_focus_search = true;
+            }
+
+            if self.keymap.pressed(i, "next_buffer") {
+                self.next_buffer();
+            }
+
+            if self.keymap.pressed(i, "prev_buffer") {
+                self.prev_buffer();
+            }
+
+            if self.keymap.pressed(i, "next_set_list_entry") {
+                self.next_set_list_entry();
+            }
+
+            if self.keymap.pressed(i, "prev_set_list_entry") {
+                self.prev_set_list_entry();
+            }
+
+            if self.keymap.pressed(i, "always_on_top") {
+                toggle_always_on_top = true;
+            }
+
+            if self.keymap.pressed(i, "normalize") {
+                normalize = true;
+            }
+
+            // Ctrl + mouse wheel, egui reports it as a zoom factor
+            if i.modifiers.ctrl {
+                zoom = i.zoom_delta();
+            }
+
+            if self.keymap.pressed(i, "format") {
+                format = true;
+            }
+
+            if self.keymap.pressed(i, "select_word") {
+                let cursor_pos = self.get_current_buffer().cursor_pos;
+                self.select_word_at(cursor_pos);
+            }
+
+            if self.keymap.pressed(i, "select_block") {
+                self.select_block_at_cursor();
+            }
+
+            if self.keymap.pressed(i, "transpose_line") {
+                transpose = true;
+            }
+
+            if self.keymap.pressed(i, "toggle_comment") {
+                toggle_comment = true;
+            }
+
+            if self.keymap.pressed(i, "pin_buffer_bar") {
+                self.buffer_bar_pinned = !self.buffer_bar_pinned;
+            }
+
+            if self.keymap.pressed(i, "send_buffer") {
+                self.send_buffer_to_sapf();
+            }
+
+            if self.keymap.pressed(i, "settings") {
+                self.show_settings = !self.show_settings;
+            }
+
+            if self.keymap.pressed(i, "patterns") {
+                self.show_patterns = !self.show_patterns;
+            }
+
+            if self.keymap.pressed(i, "set_list") {
+                self.show_set_list = !self.show_set_list;
+            }
+
+            if self.keymap.pressed(i, "toggle_theme") {
+                toggle_theme = true;
+            }
+
+            if self.keymap.pressed(i, "lock_buffer") {
+                self.toggle_buffer_lock(self.current_buffer_idx);
+            }
+
+            if self.keymap.pressed(i, "live_eval") {
+                self.toggle_buffer_live(self.current_buffer_idx);
+            }
+
+            if self.keymap.pressed(i, "reveal_buffer") {
+                self.reveal_buffer_in_file_manager(self.current_buffer_idx);
+            }
+
+            // `?` only when it wouldn't be typed into the editor
+            let question_mark = i
+                .events
+                .iter()
+                .any(|event| matches!(event, egui::Event::Text(text) if text == "?"));
+            if self.keymap.pressed(i, "help") || (question_mark && !self.editor_has_focus) {
+                self.show_help = !self.show_help;
+            }
+
+            if self.keymap.pressed(i, "dry_run") {
+                self.settings.dry_run = !self.settings.dry_run;
+                self.save_state();
+            }
+        });
+
+        if normalize {
+            self.normalize_current_buffer();
+        }
+        if transpose {
+            self.transpose_line_up();
+        }
+        if toggle_comment {
+            self.toggle_comment(selection_range);
+        }
+        if format {
+            self.format_with_external_command();
+        }
+        if zoom != 1.0 {
+            self.settings.font_size =
+                (self.settings.font_size * zoom).clamp(MIN_FONT_SIZE, MAX_FONT_SIZE);
+            self.save_state();
+        }
+        if insert_timestamp {
+            self.insert_timestamp_comment();
+        }
+        if toggle_theme {
+            let theme = if ctx.style().visuals.dark_mode {
+                egui::ThemePreference::Light
+            } else {
+                egui::ThemePreference::Dark
+            };
+            ctx.set_theme(theme);
+        }
+
+        if toggle_always_on_top {
+            let previous = self.settings.clone();
+            self.settings.always_on_top = !self.settings.always_on_top;
+            self.apply_settings_change(ctx, &previous);
+            self.save_state();
+        }
+
+        if cycle_focus {
+            self.cycle_focus(ctx);
+        }
+    }
+
+    fn handle_file_drops(&mut self, ctx: &egui::Context) {
+        let dropped = ctx.input(|i| i.raw.dropped_files.clone());
+        if dropped.is_empty() {
+            return;
+        }
+
+        let paths = dropped.iter().filter_map(dropped_file_path).collect();
+        self.open_files(paths);
+    }
+
+    fn trigger_completions(&mut self) {
+        let buffer = self.get_current_buffer();
+        let user_words = defined_words(&buffer.content);
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        if let Some(current_word) = get_current_word_for_completion(&buffer.content, cursor_byte) {
+            if !current_word.is_empty() {
+                self.completions = self
+                    .sapf_grammar
+                    .get_completions(&current_word, &user_words);
+                self.show_completions = !self.completions.is_empty();
+            } else {
+                self.completions = self.sapf_grammar.get_completions("", &user_words);
+                self.show_completions = !self.completions.is_empty();
+            }
+        } else {
+            self.completions = self.sapf_grammar.get_completions("", &user_words);
+            self.show_completions = !self.completions.is_empty();
+        }
+    }
+
+    // Literal suggestions go after the dictionary ones, they're only a typing aid
+    fn add_literal_completions(&mut self) {
+        if !self.settings.literal_completions {
+            return;
+        }
+
+        let buffer = self.get_current_buffer();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        if let Some(current_word) = get_current_word_for_completion(&buffer.content, cursor_byte) {
+            let literals = self.sapf_grammar.get_literal_completions(&current_word);
+            self.completions.extend(literals);
+            self.show_completions = !self.completions.is_empty();
+        }
+    }
+
+    // The popup keeps the editor focused, so its keys are taken before the editor sees them
+    fn handle_completion_keys(&mut self, ctx: &egui::Context) {
+        if !self.show_completions || self.completions.is_empty() {
+            return;
+        }
+
+        let mut step: isize = 0;
+        let (accept, dismiss) = ctx.input_mut(|i| {
+            let none = egui::Modifiers::NONE;
+            if i.consume_key(none, Key::ArrowDown) {
+                step += 1;
+            }
+            if i.consume_key(none, Key::ArrowUp) {
+                step -= 1;
+            }
+            if i.consume_key(none, Key::PageDown) {
+                step += COMPLETION_PAGE as isize;
+            }
+            if i.consume_key(none, Key::PageUp) {
+                step -= COMPLETION_PAGE as isize;
+            }
+            (
+                i.consume_key(none, Key::Enter),
+                i.consume_key(none, Key::Escape),
+            )
+        });
+
+        if step != 0 {
+            let last = self.completions.len() - 1;
+            self.selected_completion = self
+                .selected_completion
+                .saturating_add_signed(step)
+                .min(last);
+            self.scroll_to_completion = true;
+        }
+        if accept && let Some(item) = self.completions.get(self.selected_completion).cloned() {
+            self.accept_completion(&item);
+        }
+        // Consumed above, so Escape doesn't reach any other handler this frame
+        if dismiss {
+            self.show_completions = false;
+            self.should_focus_text_edit = true;
+        }
+    }
+
+    fn accept_completion(&mut self, item: &CompletionItem) {
+        self.show_completions = false;
+        let snippet = if self.settings.snippet_completions && item.kind == CompletionKind::Keyword {
+            usage_snippet(&item.label, &item.documentation)
+        } else {
+            None
+        };
+
+        match snippet {
+            Some(template) => self.apply_snippet(&template),
+            None => {
+                self.apply_completion(&item.label);
+                self.set_cursor(self.get_current_buffer().cursor_pos);
+            }
+        }
+    }
+
+    // Inserts the snippet and selects its first placeholder, Tab moves on to the rest
+    fn apply_snippet(&mut self, template: &str) {
+        if self.get_current_buffer().locked {
+            return;
+        }
+
+        let (text, placeholders) = expand_snippet(template);
+        self.apply_completion(&text);
+
+        let buffer = self.get_current_buffer();
+        let (cursor_pos, buffer_id) = (buffer.cursor_pos, buffer.id);
+        let content_len = buffer.content.chars().count();
+        let start = cursor_pos - text.chars().count();
+        self.snippet_buffer_id = buffer_id;
+        self.snippet_len = content_len;
+
+        let mut placeholders = placeholders
+            .into_iter()
+            .map(|range| (start + range.start, start + range.end));
+        let Some((first_start, first_end)) = placeholders.next() else {
+            self.set_cursor(cursor_pos);
+            return;
+        };
+        self.snippet_placeholders = placeholders.collect();
+        self.select_chars(first_start, first_end);
+    }
+
+    fn handle_snippet_keys(&mut self, ctx: &egui::Context) {
+        if self.snippet_placeholders.is_empty() {
+            return;
+        }
+        if self.get_current_buffer().id != self.snippet_buffer_id {
+            self.snippet_placeholders.clear();
+            return;
+        }
+        if !ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, Key::Tab)) {
+            return;
+        }
+
+        // Whatever was typed over the current placeholder moves the later ones
+        let len = self.get_current_buffer().content.chars().count();
+        let delta = len as isize - self.snippet_len as isize;
+        self.snippet_len = len;
+        let (start, end) = self.snippet_placeholders.remove(0);
+        for placeholder in &mut self.snippet_placeholders {
+            placeholder.0 = placeholder.0.saturating_add_signed(delta);
+            placeholder.1 = placeholder.1.saturating_add_signed(delta);
+        }
+        self.select_chars(
+            start.saturating_add_signed(delta).min(len),
+            end.saturating_add_signed(delta).min(len),
+        );
+    }
+
+    // Taken before the editor, which would move the cursor to the start or end
+    fn handle_history_keys(&mut self, ctx: &egui::Context) {
+        let keymap = &self.keymap;
+        let (older, newer) = ctx.input_mut(|i| {
+            (
+                keymap.consume(i, "history_older"),
+                keymap.consume(i, "history_newer"),
+            )
+        });
+        if older {
+            self.recall_history(-1);
+        } else if newer {
+            self.recall_history(1);
+        }
+    }
+
+    // Taken before the editor, its own undo would lose track of edits made outside it
+    fn handle_undo_keys(&mut self, ctx: &egui::Context) {
+        if !self.editor_has_focus {
+            return;
+        }
+        let keymap = &self.keymap;
+        // Redo first, Ctrl+Z would also take Ctrl+Shift+Z
+        let (redo, undo) =
+            ctx.input_mut(|i| (keymap.consume(i, "redo"), keymap.consume(i, "undo")));
+        if redo {
+            self.redo();
+        } else if undo {
+            self.undo();
+        }
+    }
+
+    fn undo(&mut self) {
+        let buffer = self.get_current_buffer_mut();
+        if buffer.locked {
+            return;
+        }
+        if let Some(snapshot) = buffer.history.undo(&buffer.content, buffer.cursor_pos) {
+            self.restore_snapshot(snapshot);
+        }
+    }
+
+    fn redo(&mut self) {
+        let buffer = self.get_current_buffer_mut();
+        if buffer.locked {
+            return;
+        }
+        if let Some(snapshot) = buffer.history.redo(&buffer.content, buffer.cursor_pos) {
+            self.restore_snapshot(snapshot);
+        }
+    }
+
+    fn restore_snapshot(&mut self, snapshot: Snapshot) {
+        let buffer = self.get_current_buffer_mut();
+        buffer.content = snapshot.content;
+        buffer.mark_edited();
+        self.set_cursor(snapshot.cursor_pos);
+        self.schedule_live_eval();
+        self.save_state();
+    }
+
+    // Replaces the current line, or the entry recalled last, with an older or newer entry
+    fn recall_history(&mut self, step: isize) {
+        let buffer = self.get_current_buffer();
+        if buffer.locked || self.eval_history.is_empty() {
+            return;
+        }
+
+        let (buffer_id, revision) = (buffer.id, buffer.revision);
+        let previous = self
+            .history_recall
+            .take()
+            .filter(|recall| recall.buffer_id == buffer_id && recall.revision == revision);
+        let buffer = self.get_current_buffer();
+        let (position, start, end, draft) = match previous {
+            Some(recall) => (
+                recall.position.saturating_add_signed(step),
+                recall.start,
+                recall.end,
+                recall.draft,
+            ),
+            None if step < 0 => {
+                let line_index = buffer.line_index();
+                let line =
+                    line_index.line_of_byte(char_to_byte(&buffer.content, buffer.cursor_pos));
+                let range = line_index.line_range(&buffer.content, line);
+                let start = buffer.content[..range.start].chars().count();
+                let draft = buffer.content[range.clone()].to_string();
+                (
+                    self.eval_history.len() - 1,
+                    start,
+                    start + draft.chars().count(),
+                    draft,
+                )
+            }
+            None => return,
+        };
+
+        let past_newest = position >= self.eval_history.len();
+        let text = if past_newest {
+            draft.clone()
+        } else {
+            self.eval_history[position].clone()
+        };
+        let start_byte = char_to_byte(&buffer.content, start);
+        let end_byte = char_to_byte(&buffer.content, end);
+        let content = format!(
+            "{}{}{}",
+            &buffer.content[..start_byte],
+            text,
+            &buffer.content[end_byte..]
+        );
+        let end = start + text.chars().count();
+
+        self.record_undo_point();
+        let buffer = self.get_current_buffer_mut();
+        buffer.content = content;
+        buffer.mark_edited();
+        let (buffer_id, revision) = (buffer.id, buffer.revision);
+        self.set_cursor(end);
+        if !past_newest {
+            self.history_recall = Some(HistoryRecall {
+                position,
+                buffer_id,
+                revision,
+                start,
+                end,
+                draft,
+            });
+        }
+        self.save_state();
+    }
+
+    fn editor_key(&self) -> (u64, u64, usize) {
+        let buffer = self.get_current_buffer();
+        (buffer.id, buffer.revision, buffer.cursor_pos)
+    }
+
+    fn update_completions_and_hints(&mut self) {
+        let hover_key = self.editor_key();
+        // Typing or moving the cursor elsewhere closes the popup
+        if self.show_completions && self.completions_key != Some(hover_key) {
+            self.show_completions = false;
+        }
+        if self.hover_key == Some(hover_key) {
+            return;
+        }
+        self.hover_key = Some(hover_key);
+
+        let buffer = self.get_current_buffer();
+        let cursor_byte = char_to_byte(&buffer.content, buffer.cursor_pos);
+        if let Some((word, _, _)) = get_word_at_cursor(&buffer.content, cursor_byte) {
+            self.hover_info = self.sapf_grammar.get_hover_info(&word);
+        } else {
+            self.hover_info = None;
+        }
+    }
+
+    fn show_completion_popup(&mut self, ui: &mut Ui, text_response: &Response) {
+        if self.show_completions && !self.completions.is_empty() {
+            let popup_pos = if let Some(cursor_pos) = self.get_cursor_screen_pos(ui, text_response)
+            {
+                cursor_pos + egui::vec2(0.0, 20.0)
+            } else {
+                text_response.rect.left_bottom() + egui::vec2(0.0, 5.0)
+            };
+
+            let mut hovered_docs: Option<String> = None;
+            let mut selected_docs: Option<String> = None;
+            let list = egui::Area::new(egui::Id::new("completion_popup"))
+                .fixed_pos(popup_pos)
+                .constrain(true)
+                .show(ui.ctx(), |ui| {
+                    egui::Frame::new()
+                        .corner_radius(5.0)
+                        .inner_margin(5.0)
+                        .show(ui, |ui| {
+                            ui.set_max_width(300.0);
+
+                            let mut clicked_completion: Option<CompletionItem> = None;
+                            let scroll_to_selected = std::mem::take(&mut self.scroll_to_completion);
+                            egui::ScrollArea::vertical()
+                                .max_height(COMPLETION_LIST_HEIGHT)
+                                .show(ui, |ui| {
+                                    for (i, item) in self.completions.iter().enumerate() {
+                                        let selected = i == self.selected_completion;
+                                        let response = ui.selectable_label(
+                                            selected,
+                                            format!("{} {}", item.kind.icon(), item.label),
+                                        );
+                                        if selected {
+                                            if scroll_to_selected {
+                                                response.scroll_to_me(None);
+                                            }
+                                            selected_docs = Some(item.documentation.clone());
+                                        }
+                                        if response.hovered() {
+                                            hovered_docs = Some(item.documentation.clone());
+                                        }
+                                        if response.clicked() {
+                                            clicked_completion = Some(item.clone());
+                                        }
+                                    }
+                                });
+
+                            if let Some(completion) = clicked_completion {
+                                self.accept_completion(&completion);
+                            }
+                        });
+                });
+
+            // The pointer wins over the keyboard selection, like the highlight in the list
+            if let Some(docs) = hovered_docs.or(selected_docs) {
+                show_completion_docs(ui.ctx(), list.response.rect, &docs);
+                self.hover_info = Some(docs);
+            }
+        }
+    }
+
+    fn get_cursor_screen_pos(&self, ui: &Ui, text_response: &Response) -> Option<egui::Pos2> {
+        let id = text_response.id;
+        let state = egui::TextEdit::load_state(ui.ctx(), id)?;
+        let cursor_range = state.cursor.char_range()?;
+        let cursor_index = cursor_range.primary.index;
+
+        let text_rect = text_response.rect;
+        let margin = TEXT_EDIT_MARGIN as f32;
+        let content_rect = text_rect.shrink(margin);
+
+        let buffer = self.get_current_buffer();
+        let cursor_byte = char_to_byte(&buffer.content, cursor_index);
+        let line_index = buffer.line_index();
+        let current_line_index = line_index.line_of_byte(cursor_byte);
+        let line_start = line_index
+            .line_range(&buffer.content, current_line_index)
+            .start;
+        let current_line = &buffer.content[line_start..cursor_byte];
+
+        let font_size = self.settings.font_size;
+        let char_width = font_size * CHAR_WIDTH_RATIO;
+        let line_height = font_size * LINE_HEIGHT_RATIO;
+
+        let cursor_x = content_rect.left() + (current_line.chars().count() as f32 * char_width);
+        let cursor_y = content_rect.top() + (current_line_index as f32 * line_height);
+
+        Some(egui::pos2(
+            cursor_x.min(content_rect.right()),
+            cursor_y.min(content_rect.bottom()),
+        ))
+    }
+
+    fn apply_completion(&mut self, completion: &str) {
+        if self.get_current_buffer().locked {
+            return;
+        }
+
+        let input = &self.get_current_buffer().content;
+        let cursor_byte = char_to_byte(input, self.get_current_buffer().cursor_pos);
+        let range = completion_range(input, cursor_byte, self.settings.completion_replaces_word);
+
+        let mut new_input = String::new();
+        new_input.push_str(&input[..range.start]);
+        new_input.push_str(completion);
+        new_input.push_str(&input[range.end..]);
+        let new_cursor_pos = input[..range.start].chars().count() + completion.chars().count();
+
+        self.get_current_buffer_mut().content = new_input;
+        self.get_current_buffer_mut().cursor_pos = new_cursor_pos;
+        self.get_current_buffer_mut().mark_edited();
+    }
+
+    fn show_settings_window(&mut self, ctx: &egui::Context) {
+        if !self.show_settings {
+            return;
+        }
+
+        let previous = self.settings.clone();
+        let mut open = self.show_settings;
+        let mut reload_theme = false;
+        egui::Window::new("settings")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                self.settings.ui(ui, &self.keymap);
+                ui.checkbox(&mut self.ephemeral, "ephemeral session (don't save state)");
+                ui.horizontal(|ui| {
+                    if ui.button("show log").clicked() {
+                        self.show_log = true;
+                    }
+                    if ui.button("reload theme").clicked() {
+                        reload_theme = true;
+                    }
+                });
+                ui.separator();
+                self.connections_ui(ui);
+            });
+        self.show_settings = open;
+
+        if reload_theme {
+            self.reload_theme(ctx);
+        }
+        if self.settings != previous {
+            self.apply_settings_change(ctx, &previous);
+            self.save_state();
+        }
+    }
+
+    fn connections_ui(&mut self, ui: &mut Ui) {
+        ui.label("sapf engines (the first one is the default for buffers):");
+        let mut remove = None;
+        for (idx, name) in self.settings.connections.iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(name);
+                if idx > 0 && ui.small_button("x").clicked() {
+                    remove = Some(idx);
+                }
+            });
+        }
+        if let Some(idx) = remove {
+            self.settings.connections.remove(idx);
+        }
+
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.new_connection_name);
+            let name = self.new_connection_name.trim().to_string();
+            let can_add = !name.is_empty() && !self.settings.connections.contains(&name);
+            if ui.add_enabled(can_add, egui::Button::new("add")).clicked() {
+                self.settings.connections.push(name);
+                self.new_connection_name.clear();
+            }
+        });
+    }
+
+    fn show_help_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_help;
+        egui::Window::new("help")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .default_width(420.0)
+            .show(ctx, |ui| help_ui(ui, &self.keymap));
+        self.show_help = open;
+    }
+
+    fn show_regions_window(&mut self, ctx: &egui::Context) {
+        if !self.show_regions {
+            return;
+        }
+
+        let regions = self.current_regions();
+        let mut jump_to = None;
+        let mut eval = None;
+        let mut open = self.show_regions;
+        egui::Window::new("regions")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                if regions.is_empty() {
+                    ui.weak(format!(
+                        "No regions, mark one with \"{} name\" ... \"{}\"",
+                        self.settings.region_start, self.settings.region_end
+                    ));
+                }
+                for region in &regions {
+                    ui.horizontal(|ui| {
+                        if ui.small_button("eval").clicked() {
+                            eval = Some(region.body(&self.get_current_buffer().content));
+                        }
+                        let label = if region.name.is_empty() {
+                            "(unnamed)"
+                        } else {
+                            region.name.as_str()
+                        };
+                        if ui.selectable_label(false, label).clicked() {
+                            jump_to = Some(region.start_line + 1);
+                        }
+                    });
+                }
+            });
+        self.show_regions = open;
+
+        if let Some(line) = jump_to {
+            self.go_to_line(line);
+        }
+        if let Some(code) = eval {
+            self.eval(&code);
+        }
+    }
+
+    fn show_search_window(&mut self, ctx: &egui::Context) {
+        if !self.show_search {
+            return;
+        }
+
+        let mut open = self.show_search;
+        let mut jump_to: Option<(usize, usize)> = None;
+        egui::Window::new("search all buffers")
+            .open(&mut open)
+            .collapsible(false)
+            .default_width(360.0)
+            .show(ctx, |ui| {
+                let query_response = ui.text_edit_singleline(&mut self.search_query);
+                if self.should_focus_search {
+                    query_response.request_focus();
+                    self.should_focus_search = false;
+                }
+
+                ui.add_space(5.0);
+
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        let names = display_names(&self.buffers);
+                        for (idx, buffer) in self.buffers.iter().enumerate() {
+                            let matches = find_in_content(&buffer.content, &self.search_query);
+                            if matches.is_empty() {
+                                continue;
+                            }
+
+                            ui.strong(&names[idx]);
+                            for line_match in matches {
+                                let label =
+                                    format!("{:>4}: {}", line_match.line_number, line_match.line);
+                                if ui.selectable_label(false, label).clicked() {
+                                    jump_to = Some((idx, line_match.char_offset));
+                                }
+                            }
+                        }
+                    });
+            });
+        self.show_search = open;
+
+        if let Some((idx, char_offset)) = jump_to {
+            self.switch_to_buffer(idx);
+            self.set_cursor(char_offset);
+        }
+    }
+
+    fn show_new_session_dialog(&mut self, ctx: &egui::Context) {
+        if !self.confirm_new_session {
+            return;
+        }
+
+        let mut confirmed = false;
+        let mut cancelled = false;
+        egui::Window::new("new session")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("Some buffers have unsaved changes. Discard them and start over?");
+                ui.add_space(5.0);
+                ui.horizontal(|ui| {
+                    if ui.button("discard").clicked() {
+                        confirmed = true;
+                    }
+                    if ui.button("cancel").clicked() {
+                        cancelled = true;
+                    }
+                });
+            });
+
+        if confirmed || cancelled {
+            self.confirm_new_session = false;
+        }
+        if confirmed {
+            self.start_new_session();
+        }
+    }
+
+    fn show_close_buffer_dialog(&mut self, ctx: &egui::Context) {
+        let Some(buffer_id) = self.confirm_close_buffer else {
+            return;
+        };
+        let Some(idx) = self.buffers.iter().position(|b| b.id == buffer_id) else {
+            self.confirm_close_buffer = None;
+            return;
+        };
+
+        let mut save = false;
+        let mut discard = false;
+        let mut cancel = false;
+        egui::Window::new("close buffer")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} has unsaved changes. Save them before closing?",
+                    self.buffers[idx].name
+                ));
+                ui.add_space(5.0);
+                ui.horizontal(|ui| {
+                    if ui.button("save").clicked() {
+                        save = true;
+                    }
+                    if ui.button("discard").clicked() {
+                        discard = true;
+                    }
+                    if ui.button("cancel").clicked() {
+                        cancel = true;
+                    }
+                });
+            });
+
+        if save || discard || cancel {
+            self.confirm_close_buffer = None;
+        }
+        // A save that fails or is cancelled in the dialog keeps the buffer open
+        if discard || (save && self.save_buffer(idx)) {
+            self.close_buffer(idx);
+        }
+    }
+
+    fn show_buffer_cap_dialog(&mut self, ctx: &egui::Context) {
+        if !self.confirm_buffer_cap {
+            return;
         }
-    }
 
-    fn update_completions_and_hints(&mut self) {
-        if let Some((word, _, _)) = get_word_at_cursor(
-            &self.get_current_buffer().content,
-            self.get_current_buffer().cursor_pos,
-        ) {
-            self.hover_info = self.sapf_grammar.get_hover_info(&word);
-        } else {
-            self.hover_info = None;
+        let candidates = self.buffer_cap_candidates();
+        let mut close = false;
+        let mut keep = false;
+        egui::Window::new("too many buffers")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} buffers are open, the limit is {}.",
+                    self.buffers.len(),
+                    self.settings.max_buffers
+                ));
+                if candidates.is_empty() {
+                    ui.label("All other buffers have unsaved changes.");
+                } else {
+                    ui.label("Close these least recently used unmodified buffers?");
+                    for buffer in self.buffers.iter().filter(|b| candidates.contains(&b.id)) {
+                        ui.weak(&buffer.name);
+                    }
+                }
+                ui.add_space(5.0);
+                ui.horizontal(|ui| {
+                    if !candidates.is_empty() && ui.button("close them").clicked() {
+                        close = true;
+                    }
+                    if ui.button("keep all").clicked() {
+                        keep = true;
+                    }
+                });
+            });
+
+        if close || keep {
+            self.confirm_buffer_cap = false;
+        }
+        if close {
+            self.close_buffers_by_id(&candidates);
         }
     }
 
-    fn show_completion_popup(&mut self, ui: &mut Ui, text_response: &Response) {
-        if self.show_completions && !self.completions.is_empty() {
-            let popup_pos = if let Some(cursor_pos) = self.get_cursor_screen_pos(ui, text_response)
-            {
-                cursor_pos + egui::vec2(0.0, 20.0)
-            } else {
-                text_response.rect.left_bottom() + egui::vec2(0.0, 5.0)
-            };
+    fn show_docs_search_window(&mut self, ctx: &egui::Context) {
+        if !self.show_docs_search {
+            return;
+        }
 
-            egui::Area::new(egui::Id::new("completion_popup"))
-                .fixed_pos(popup_pos)
-                .show(ui.ctx(), |ui| {
-                    egui::Frame::new()
-                        .corner_radius(5.0)
-                        .inner_margin(5.0)
-                        .show(ui, |ui| {
-                            ui.set_max_width(300.0);
+        let mut open = self.show_docs_search;
+        let mut insert: Option<String> = None;
+        egui::Window::new("search docs")
+            .open(&mut open)
+            .collapsible(false)
+            .default_width(360.0)
+            .show(ctx, |ui| {
+                let query_response = ui.text_edit_singleline(&mut self.docs_query);
+                if self.should_focus_docs_search {
+                    query_response.request_focus();
+                    self.should_focus_docs_search = false;
+                }
 
-                            // TODO This is very stupid and inelegant
-                            let mut selected_completion: Option<String> = None;
-                            for (i, item) in self.completions.iter().enumerate() {
-                                if i >= 10 {
-                                    break;
-                                }
-                                let response = ui.selectable_label(false, &item.label);
-                                if i == 0 && self.should_focus_completions {
-                                    response.request_focus();
-                                    self.should_focus_completions = false;
-                                }
-                                if response.has_focus() {
-                                    self.hover_info = Some(item.documentation.clone());
-                                    egui::Area::new(egui::Id::new("docs"))
-                                        .fixed_pos(popup_pos + egui::vec2(80.0, 0.0))
-                                        .show(ui.ctx(), |ui| {
-                                            egui::Frame::new()
-                                                .corner_radius(5.0)
-                                                .inner_margin(5.0)
-                                                .show(ui, |ui| {
-                                                    ui.set_max_width(300.0);
-                                                    ui.label(&item.documentation);
-                                                });
-                                        });
-                                }
-                                if response.clicked() {
-                                    selected_completion = Some(item.label.clone());
-                                }
+                ui.add_space(5.0);
 
-                                if response.lost_focus() {
-                                    self.should_focus_text_edit = true;
-                                    self.show_completions = false;
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for item in self.sapf_grammar.search_docs(&self.docs_query) {
+                            ui.horizontal(|ui| {
+                                if ui.small_button("insert").clicked() {
+                                    insert = Some(item.label.clone());
                                 }
-                            }
+                                ui.strong(&item.label);
+                            });
+                            ui.weak(&item.documentation);
+                            ui.add_space(4.0);
+                        }
+                    });
+            });
+        self.show_docs_search = open;
 
-                            if let Some(completion) = selected_completion {
-                                self.apply_completion(&completion);
-                                self.show_completions = false;
-                                self.last_completion_cursor =
-                                    Some(self.get_current_buffer().cursor_pos);
-                                self.should_focus_text_edit = true;
-                            }
-                        });
-                });
+        if let Some(label) = insert {
+            self.insert_at_cursor(&label);
         }
     }
 
-    fn get_cursor_screen_pos(&self, ui: &Ui, text_response: &Response) -> Option<egui::Pos2> {
-        let id = text_response.id;
-        let state = egui::TextEdit::load_state(ui.ctx(), id)?;
-        let cursor_range = state.cursor.char_range()?;
-        let cursor_index = cursor_range.primary.index;
-
-        let text_rect = text_response.rect;
-        let margin = TEXT_EDIT_MARGIN as f32;
-        let content_rect = text_rect.shrink(margin);
-
-        let text_to_cursor = if cursor_index <= self.get_current_buffer().content.len() {
-            &self.get_current_buffer().content[..cursor_index]
-        } else {
-            &self.get_current_buffer().content
-        };
+    fn show_set_list_window(&mut self, ctx: &egui::Context) {
+        if !self.show_set_list {
+            return;
+        }
 
-        let current_line_index = text_to_cursor.lines().count().saturating_sub(1);
-        let current_line = text_to_cursor.lines().last().unwrap_or("");
+        let mut open = self.show_set_list;
+        let mut add_current = false;
+        let mut go_to: Option<usize> = None;
+        let mut move_up: Option<usize> = None;
+        let mut remove: Option<usize> = None;
+        egui::Window::new("set list")
+            .open(&mut open)
+            .collapsible(false)
+            .default_width(260.0)
+            .show(ctx, |ui| {
+                if ui.button("add current buffer").clicked() {
+                    add_current = true;
+                }
 
-        let font_size = ui
-            .style()
-            .text_styles
-            .get(&egui::TextStyle::Body)
-            .map(|font| font.size)
-            .unwrap_or(DEFAULT_FONT_SIZE);
+                ui.add_space(5.0);
 
-        let char_width = font_size * CHAR_WIDTH_RATIO;
-        let line_height = font_size * LINE_HEIGHT_RATIO;
+                let names = display_names(&self.buffers);
+                for (position, id) in self.set_list.iter().enumerate() {
+                    let Some(idx) = self.buffers.iter().position(|buffer| buffer.id == *id) else {
+                        continue;
+                    };
 
-        let cursor_x = content_rect.left() + (current_line.chars().count() as f32 * char_width);
-        let cursor_y = content_rect.top() + (current_line_index as f32 * line_height);
+                    ui.horizontal(|ui| {
+                        let is_current = self.set_list_position == Some(position);
+                        let label = format!("{}. {}", position + 1, names[idx]);
+                        if ui.selectable_label(is_current, label).clicked() {
+                            go_to = Some(position);
+                        }
+                        if position > 0 && ui.small_button("up").clicked() {
+                            move_up = Some(position);
+                        }
+                        if ui.small_button("x").clicked() {
+                            remove = Some(position);
+                        }
+                    });
+                }
+            });
+        self.show_set_list = open;
 
-        Some(egui::pos2(
-            cursor_x.min(content_rect.right()),
-            cursor_y.min(content_rect.bottom()),
-        ))
+        if add_current {
+            self.add_to_set_list(self.current_buffer_idx);
+        }
+        if let Some(position) = move_up {
+            self.set_list.swap(position, position - 1);
+            self.save_state();
+        }
+        if let Some(position) = remove {
+            self.set_list.remove(position);
+            self.prune_set_list();
+            self.save_state();
+        }
+        if let Some(position) = go_to {
+            self.go_to_set_list_entry(position);
+        }
     }
 
-    fn apply_completion(&mut self, completion: &str) {
-        let cursor_pos = self.get_current_buffer().cursor_pos;
-        let input = &self.get_current_buffer().content;
-        let mut word_start = cursor_pos;
-        let bytes = input.as_bytes();
-        while word_start > 0 {
-            let c = bytes[word_start - 1];
-            if !c.is_ascii_alphanumeric() && c != b'_' && c != b'.' {
-                break;
-            }
-            word_start -= 1;
+    fn show_patterns_window(&mut self, ctx: &egui::Context) {
+        if !self.show_patterns {
+            return;
         }
 
-        let mut new_input = String::new();
-        new_input.push_str(&input[..word_start]);
-        new_input.push_str(completion);
-        new_input.push_str(&input[cursor_pos..]);
-        let new_cursor_pos = word_start + completion.len();
+        let mut open = self.show_patterns;
+        let mut reload = false;
+        let mut trigger: Option<String> = None;
+        egui::Window::new("patterns")
+            .open(&mut open)
+            .collapsible(false)
+            .default_width(260.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("reload").clicked() {
+                        reload = true;
+                    }
+                    if let Ok(path) = get_patterns_file_path() {
+                        ui.weak(path.display().to_string());
+                    }
+                });
 
-        self.get_current_buffer_mut().content = new_input;
-        self.get_current_buffer_mut().cursor_pos = new_cursor_pos;
+                ui.add_space(5.0);
+
+                if self.patterns.is_empty() {
+                    ui.weak("No patterns yet. Add `name = sapf code` lines to the file above.");
+                }
+
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for pattern in &self.patterns {
+                            if ui
+                                .button(&pattern.name)
+                                .on_hover_text(&pattern.code)
+                                .clicked()
+                            {
+                                trigger = Some(pattern.code.clone());
+                            }
+                        }
+                    });
+            });
+        self.show_patterns = open;
+
+        if reload {
+            self.reload_patterns();
+        }
+        if let Some(code) = trigger {
+            self.send_to_sapf(&code);
+        }
     }
-}
 
-impl eframe::App for SapfAsPlainText {
-    fn clear_color(&self, _visuals: &egui::Visuals) -> [f32; 4] {
-        egui::Rgba::TRANSPARENT.to_array()
+    // A size or position saved on a bigger monitor is pulled back onto this one,
+    // after that the geometry just follows the window until it's saved
+    fn track_window_geometry(&mut self, ctx: &egui::Context) {
+        // Clamped once the monitor size shows up, some platforms never report it and
+        // the geometry is tracked regardless
+        if !self.window_checked
+            && let Some(monitor) = ctx.input(|i| i.viewport().monitor_size)
+        {
+            self.window_checked = true;
+            if let Some(geometry) = self.window_geometry {
+                let clamped = geometry.clamped_to(monitor);
+                if clamped != geometry {
+                    ctx.send_viewport_cmd(egui::ViewportCommand::InnerSize(clamped.size()));
+                    ctx.send_viewport_cmd(egui::ViewportCommand::OuterPosition(clamped.position()));
+                    self.window_geometry = Some(clamped);
+                }
+            }
+            return;
+        }
+
+        if let Some(geometry) = current_geometry(ctx) {
+            self.window_geometry = Some(geometry);
+        }
     }
 
-    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
-        self.save_state();
+    fn apply_settings_change(&mut self, ctx: &egui::Context, previous: &Settings) {
+        if self.settings.log_level != previous.log_level {
+            log::set_level(self.settings.log_level);
+        }
+        if self.settings.transparent != previous.transparent {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Transparent(
+                self.settings.transparent,
+            ));
+        }
+        if self.settings.always_on_top != previous.always_on_top {
+            ctx.send_viewport_cmd(egui::ViewportCommand::WindowLevel(
+                self.settings.window_level(),
+            ));
+        }
+        if self.settings.connection_names() != previous.connection_names() {
+            self.sync_connections();
+            self.check_buffer_connections();
+        }
     }
+}
 
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+impl SapfAsPlainText {
+    fn update_frame(&mut self, ctx: &egui::Context) {
         self.update_output();
         self.handle_key_input(ctx);
+        self.process_live_eval(ctx);
+        self.process_send_queue(ctx);
         self.update_completions_and_hints();
+        self.update_bracket_check(ctx);
+        self.update_minimap(ctx);
         self.handle_file_drops(ctx);
+        self.process_file_batch(ctx);
+        self.run_pending_autorun();
+        self.track_window_geometry(ctx);
+        self.autosave(ctx);
         let hover_info = self.hover_info.clone().unwrap_or_default();
 
-        custom_window_frame(ctx, WINDOW_TITLE, |ui| {
+        custom_window_frame(ctx, WINDOW_TITLE, self.settings.window_opacity(), |ui| {
+            let console_frame =
+                egui::Frame::side_top_panel(ui.style()).fill(egui::Color32::TRANSPARENT);
+            let status_bar_frame = console_frame;
+            let editor_panel = egui::CentralPanel::default()
+                .frame(egui::Frame::central_panel(ui.style()).fill(egui::Color32::TRANSPARENT));
+
+            egui::TopBottomPanel::bottom("status_bar")
+                .frame(status_bar_frame)
+                .show_separator_line(false)
+                .show_inside(ui, |ui| self.status_bar_ui(ui));
+
+            let transcript = self.settings.console_layout == ConsoleLayout::Transcript;
+            let console_height = if transcript {
+                40.0
+            } else {
+                self.settings.console_height
+            };
+
+            let console_collapsed = self.settings.console_collapsed;
             egui::TopBottomPanel::bottom("console")
+                .frame(console_frame)
                 .show_separator_line(false)
-                .exact_height(180.0)
-                .show_inside(ui, |ui| {
+                .exact_height(console_height)
+                .show_animated_inside(ui, !console_collapsed, |ui| {
+                    if !transcript {
+                        self.console_divider_ui(ui);
+                    }
                     ui.vertical(|ui| {
                         ui.label(hover_info);
-                        ui.add_space(10.0);
-                        egui::ScrollArea::vertical()
-                            .stick_to_bottom(true)
-                            .show(ui, |ui| {
-                                ui.add(
-                                    egui::TextEdit::multiline(&mut self.from_sapf)
-                                        .desired_width(ui.available_width())
-                                        .margin(Margin::same(TEXT_EDIT_MARGIN))
-                                        .frame(false)
-                                        .interactive(false),
-                                );
-                            });
+                        if !transcript {
+                            ui.add_space(10.0);
+                            self.console_ui(ui, self.settings.echo_sent);
+                        }
                     });
                 });
 
-            egui::CentralPanel::default().show_inside(ui, |ui| {
+            if transcript && !console_collapsed {
+                egui::SidePanel::right("transcript")
+                    .frame(console_frame)
+                    .resizable(true)
+                    .default_width(260.0)
+                    .show_inside(ui, |ui| self.console_ui(ui, true));
+            }
+
+            editor_panel.show_inside(ui, |ui| {
                 let pointer_pos = ui.ctx().pointer_latest_pos();
                 let top_hover_height = 45.0;
 
@@ -893,26 +4844,103 @@ impl eframe::App for SapfAsPlainText {
                     let ui_rect = ui.max_rect();
                     self.show_buffer_bar = pos.y <= ui_rect.min.y + top_hover_height;
                 }
+                self.show_buffer_bar |= self.buffer_bar_pinned;
 
                 let mut switch_to_buffer: Option<usize> = None;
                 let mut close_buffer: Option<usize> = None;
+                let mut toggle_lock: Option<usize> = None;
+                let mut reveal_buffer: Option<usize> = None;
+                let mut toggle_live: Option<usize> = None;
+                let mut edit_autorun: Option<usize> = None;
+                let mut edit_name: Option<usize> = None;
+                // Dragged buffer id and the tab it was dropped on
+                let mut move_buffer: Option<(u64, usize)> = None;
+                let mut compare: Option<(usize, CompareTarget)> = None;
+                let mut set_connection: Option<(usize, String)> = None;
                 let mut create_new = false;
+                let mut save_buffer = false;
                 let mut export_buffer = false;
                 let mut load_file = false;
+                let mut toggle_settings = false;
+                let mut open_search = false;
+                let mut toggle_patterns = false;
+                let mut toggle_set_list = false;
+                let mut open_docs_search = false;
+                let mut new_session = false;
 
                 if self.show_buffer_bar {
                     ui.vertical(|ui| {
                         ui.horizontal(|ui| {
                             global_theme_preference_switch(ui);
-                            if ui.small_button("add").clicked() {
+                            if ui
+                                .small_button("add")
+                                .on_hover_text(self.keymap.label("new_buffer"))
+                                .clicked()
+                            {
                                 create_new = true;
                             }
-                            if ui.button("open").clicked() {
+                            if ui
+                                .button("open")
+                                .on_hover_text(self.keymap.label("open"))
+                                .clicked()
+                            {
                                 load_file = true;
                             }
-                            if ui.button("export").clicked() {
+                            if ui
+                                .button("save")
+                                .on_hover_text(self.keymap.label("save"))
+                                .clicked()
+                            {
+                                save_buffer = true;
+                            }
+                            if ui
+                                .button("save as")
+                                .on_hover_text(self.keymap.label("save_as"))
+                                .clicked()
+                            {
                                 export_buffer = true;
                             }
+                            let new_session_button = ui
+                                .button("new session")
+                                .on_hover_text(self.keymap.label("new_session"));
+                            if new_session_button.clicked() {
+                                new_session = true;
+                            }
+                            if ui
+                                .button("search")
+                                .on_hover_text(self.keymap.label("search"))
+                                .clicked()
+                            {
+                                open_search = true;
+                            }
+                            if ui
+                                .button("docs")
+                                .on_hover_text(self.keymap.label("docs_search"))
+                                .clicked()
+                            {
+                                open_docs_search = true;
+                            }
+                            if ui
+                                .button("patterns")
+                                .on_hover_text(self.keymap.label("patterns"))
+                                .clicked()
+                            {
+                                toggle_patterns = true;
+                            }
+                            if ui
+                                .button("set list")
+                                .on_hover_text(self.keymap.label("set_list"))
+                                .clicked()
+                            {
+                                toggle_set_list = true;
+                            }
+                            if ui
+                                .button("settings")
+                                .on_hover_text(self.keymap.label("settings"))
+                                .clicked()
+                            {
+                                toggle_settings = true;
+                            }
                         });
 
                         ui.add_space(5.0);
@@ -924,17 +4952,142 @@ impl eframe::App for SapfAsPlainText {
                                 ui.horizontal(|ui| {
                                     ui.spacing_mut().item_spacing.x = 2.0;
 
+                                    let names = display_names(&self.buffers);
                                     for (idx, buffer) in self.buffers.iter().enumerate() {
-                                        let label = if buffer.is_modified {
-                                            format!("{} *", buffer.name)
+                                        let mut label = if buffer.is_modified {
+                                            format!("{} *", names[idx])
                                         } else {
-                                            buffer.name.clone()
+                                            names[idx].clone()
                                         };
+                                        if buffer.locked {
+                                            label = format!("🔒 {}", label);
+                                        }
+                                        if buffer.live {
+                                            label = format!("{} (live)", label);
+                                        }
+                                        if let Some(name) = &buffer.connection_id {
+                                            label = format!("{} @{}", label, name);
+                                        }
 
                                         let is_current = idx == self.current_buffer_idx;
-                                        if ui.selectable_label(is_current, &label).clicked() {
+                                        let tooltip = match &buffer.file_path {
+                                            Some(path) => path.display().to_string(),
+                                            None => buffer.name.clone(),
+                                        };
+                                        let tab = ui
+                                            .selectable_label(is_current, &label)
+                                            .on_hover_text(tooltip)
+                                            .interact(egui::Sense::drag());
+                                        tab.dnd_set_drag_payload(buffer.id);
+                                        if tab.dragged() {
+                                            ui.ctx().set_cursor_icon(egui::CursorIcon::Grabbing);
+                                        }
+                                        // Marks the side the dragged tab ends up on
+                                        let dragged_from =
+                                            tab.dnd_hover_payload::<u64>().and_then(|id| {
+                                                self.buffers.iter().position(|b| b.id == *id)
+                                            });
+                                        if let Some(from) = dragged_from.filter(|&from| from != idx)
+                                        {
+                                            let x = if from < idx {
+                                                tab.rect.right() + 1.0
+                                            } else {
+                                                tab.rect.left() - 1.0
+                                            };
+                                            ui.painter().vline(
+                                                x,
+                                                tab.rect.y_range(),
+                                                ui.visuals().selection.stroke,
+                                            );
+                                        }
+                                        if let Some(id) = tab.dnd_release_payload::<u64>() {
+                                            move_buffer = Some((*id, idx));
+                                        }
+                                        if tab.clicked() {
                                             switch_to_buffer = Some(idx);
                                         }
+                                        if tab.double_clicked() {
+                                            edit_name = Some(idx);
+                                        }
+                                        tab.context_menu(|ui| {
+                                            let lock_label =
+                                                if buffer.locked { "unlock" } else { "lock" };
+                                            if ui.button(lock_label).clicked() {
+                                                toggle_lock = Some(idx);
+                                                ui.close_menu();
+                                            }
+                                            let live_label = if buffer.live {
+                                                "stop live eval"
+                                            } else {
+                                                "live eval"
+                                            };
+                                            if ui.button(live_label).clicked() {
+                                                toggle_live = Some(idx);
+                                                ui.close_menu();
+                                            }
+                                            if ui.button("rename...").clicked() {
+                                                edit_name = Some(idx);
+                                                ui.close_menu();
+                                            }
+                                            if ui.button("autorun snippet...").clicked() {
+                                                edit_autorun = Some(idx);
+                                                ui.close_menu();
+                                            }
+                                            ui.menu_button("compare with...", |ui| {
+                                                if buffer.file_path.is_some()
+                                                    && ui.button("saved file").clicked()
+                                                {
+                                                    compare = Some((idx, CompareTarget::SavedFile));
+                                                    ui.close_menu();
+                                                }
+                                                for (other_idx, other) in
+                                                    self.buffers.iter().enumerate()
+                                                {
+                                                    if other_idx != idx
+                                                        && ui.button(&other.name).clicked()
+                                                    {
+                                                        compare = Some((
+                                                            idx,
+                                                            CompareTarget::Buffer(other_idx),
+                                                        ));
+                                                        ui.close_menu();
+                                                    }
+                                                }
+                                            });
+                                            let reveal = ui
+                                                .add_enabled(
+                                                    buffer.file_path.is_some(),
+                                                    egui::Button::new("reveal in file manager"),
+                                                )
+                                                .on_disabled_hover_text(
+                                                    "Save the buffer first, it isn't on disk yet",
+                                                );
+                                            if reveal.clicked() {
+                                                reveal_buffer = Some(idx);
+                                                ui.close_menu();
+                                            }
+                                            if self.connections.len() > 1 {
+                                                let target = buffer
+                                                    .connection_id
+                                                    .as_ref()
+                                                    .unwrap_or(&self.connections[0].name);
+                                                ui.menu_button("sapf engine", |ui| {
+                                                    for connection in &self.connections {
+                                                        let selected = &connection.name == target;
+                                                        if ui
+                                                            .radio(selected, &connection.name)
+                                                            .clicked()
+                                                        {
+                                                            set_connection = Some((
+                                                                idx,
+                                                                connection.name.clone(),
+                                                            ));
+                                                            ui.close_menu();
+                                                        }
+                                                    }
+                                                });
+                                            }
+                                        });
                                         if ui.small_button("x").clicked() {
                                             close_buffer = Some(idx);
                                         }
@@ -949,7 +5102,7 @@ impl eframe::App for SapfAsPlainText {
                             egui::Color32::TRANSPARENT;
 
                         ui.label("•");
-                        ui.label(&self.buffers[self.current_buffer_idx].name);
+                        ui.label(&display_names(&self.buffers)[self.current_buffer_idx]);
                     });
                     ui.add_space(2.0);
                 }
@@ -960,56 +5113,371 @@ impl eframe::App for SapfAsPlainText {
                 if create_new {
                     self.create_new_buffer();
                 }
+                if let Some(idx) = toggle_lock {
+                    self.toggle_buffer_lock(idx);
+                }
+                if let Some(idx) = toggle_live {
+                    self.toggle_buffer_live(idx);
+                }
+                if let Some(idx) = edit_autorun {
+                    self.edit_buffer_autorun(idx);
+                }
+                if let Some(idx) = edit_name {
+                    self.edit_buffer_name(idx);
+                }
+                if let Some((buffer_id, to)) = move_buffer {
+                    self.move_buffer(buffer_id, to);
+                }
+                if let Some((idx, target)) = compare {
+                    self.compare_buffer(idx, target);
+                }
+                if let Some(idx) = reveal_buffer {
+                    self.reveal_buffer_in_file_manager(idx);
+                }
+                if let Some((idx, name)) = set_connection {
+                    self.set_buffer_connection(idx, name);
+                }
                 if let Some(idx) = close_buffer {
-                    self.close_buffer(idx);
+                    self.request_close_buffer(idx);
+                }
+                if save_buffer {
+                    self.save_buffer(self.current_buffer_idx);
                 }
                 if export_buffer {
-                    self.export_current_buffer();
+                    self.export_buffer(self.current_buffer_idx);
                 }
                 if load_file {
                     self.load_file_into_new_buffer();
                 }
+                if toggle_settings {
+                    self.show_settings = !self.show_settings;
+                }
+                if open_search {
+                    self.show_search = true;
+                    self.should_focus_search = true;
+                }
+                if toggle_patterns {
+                    self.show_patterns = !self.show_patterns;
+                }
+                if toggle_set_list {
+                    self.show_set_list = !self.show_set_list;
+                }
+                if open_docs_search {
+                    self.show_docs_search = true;
+                    self.should_focus_docs_search = true;
+                }
+                if new_session {
+                    self.request_new_session();
+                }
 
-                egui::ScrollArea::vertical().show(ui, |ui| {
-                    let input = ui.add(
-                        egui::TextEdit::multiline(&mut self.get_current_buffer_mut().content)
-                            .desired_width(ui.available_width())
-                            .desired_rows(35)
-                            .margin(Margin::same(TEXT_EDIT_MARGIN))
-                            .frame(false),
-                    );
+                if self.settings.show_minimap {
+                    self.minimap_panel(ui);
+                }
+
+                let mut editor_scroll = egui::ScrollArea::vertical();
+                if let Some(offset) = self.minimap_jump.take() {
+                    editor_scroll = editor_scroll.vertical_scroll_offset(offset);
+                }
+                let editor_scroll_output = editor_scroll.show(ui, |ui| {
+                    let editor_id = egui::Id::new(EDITOR_ID);
+                    let pending_cursor = self.pending_cursor.take();
+                    if let Some(range) = pending_cursor {
+                        let mut state =
+                            egui::TextEdit::load_state(ui.ctx(), editor_id).unwrap_or_default();
+                        state.cursor.set_char_range(Some(range));
+                        state.store(ui.ctx(), editor_id);
+                    }
+
+                    let buffer = &mut self.buffers[self.current_buffer_idx];
+                    buffer.history.seed(&buffer.content, buffer.cursor_pos);
+                    let mut locked_text;
+                    let text: &mut dyn egui::TextBuffer = if buffer.locked {
+                        locked_text = buffer.content.as_str();
+                        &mut locked_text
+                    } else {
+                        &mut buffer.content
+                    };
+
+                    let output = egui::TextEdit::multiline(text)
+                        .id(editor_id)
+                        .desired_width(ui.available_width())
+                        .desired_rows(35)
+                        .font(egui::FontId::proportional(self.settings.font_size))
+                        .margin(Margin::same(TEXT_EDIT_MARGIN))
+                        .frame(false)
+                        .show(ui);
+                    let input = output.response;
+                    self.paint_error_squiggle(ui, &output.galley, output.galley_pos);
+                    self.paint_bracket_error(ui, &output.galley, output.galley_pos);
+                    self.paint_evaluated_lines(ui, &output.galley, output.galley_pos);
+                    self.paint_armed_lines(ui, &output.galley, output.galley_pos);
+                    self.paint_whitespace(ui, &output.galley, output.galley_pos);
+
+                    if let Some(range) = pending_cursor {
+                        let cursor_rect = output
+                            .galley
+                            .pos_from_ccursor(range.primary)
+                            .translate(output.galley_pos.to_vec2());
+                        ui.scroll_to_rect(cursor_rect, Some(egui::Align::Center));
+                    }
 
                     if self.should_focus_text_edit {
                         input.request_focus();
                         self.should_focus_text_edit = false;
                     }
+                    self.editor_has_focus = input.has_focus();
 
-                    if input.changed() || input.has_focus() {
-                        let id = input.id;
-                        if let Some(mut state) = egui::TextEdit::load_state(ui.ctx(), id) {
-                            if self.last_completion_cursor.is_some() {
-                                state
-                                    .cursor
-                                    .set_char_range(Some(egui::text::CCursorRange::one(
-                                        CCursor::new(self.get_current_buffer().cursor_pos),
-                                    )));
-                                state.store(ui.ctx(), id);
-                                self.last_completion_cursor = None;
-                            } else if let Some(cursor_range) = state.cursor.char_range() {
-                                self.get_current_buffer_mut().cursor_pos =
-                                    cursor_range.primary.index;
-                                self.get_current_buffer_mut().is_modified = true;
-                            }
-                        }
+                    if (input.changed() || input.has_focus())
+                        && let Some(cursor_range) = output.state.cursor.char_range()
+                    {
+                        self.get_current_buffer_mut().cursor_pos = cursor_range.primary.index;
+                    }
+
+                    // Redo egui's double click word selection with sapf's boundaries
+                    if input.double_clicked() {
+                        let cursor_pos = self.get_current_buffer().cursor_pos;
+                        self.select_word_at(cursor_pos);
                     }
 
                     if input.changed() {
+                        self.get_current_buffer_mut().mark_edited();
+                        self.schedule_live_eval();
                         self.save_state();
                     }
 
                     self.show_completion_popup(ui, &input);
                 });
+                self.editor_viewport = (
+                    editor_scroll_output.state.offset.y,
+                    editor_scroll_output.content_size.y,
+                    editor_scroll_output.inner_rect.height(),
+                );
             });
         });
+
+        self.show_settings_window(ctx);
+        self.show_help_window(ctx);
+        self.show_regions_window(ctx);
+        self.show_definitions_window(ctx);
+        self.show_log_window(ctx);
+        self.show_scratch_input(ctx);
+        self.show_goto_line_input(ctx);
+        self.show_autorun_window(ctx);
+        self.show_rename_window(ctx);
+        self.show_diff_window(ctx);
+        self.show_search_window(ctx);
+        self.show_patterns_window(ctx);
+        self.show_set_list_window(ctx);
+        self.show_docs_search_window(ctx);
+        self.show_new_session_dialog(ctx);
+        self.show_close_buffer_dialog(ctx);
+        self.show_buffer_cap_dialog(ctx);
+    }
+
+    // Logs a panic from a frame, puts the state back into a usable shape and
+    // saves it, so a bug costs one frame rather than the session
+    fn recover_from_panic(&mut self, payload: Box<dyn std::any::Any + Send>) {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        log::error!("Recovered from a panic: {}", message);
+
+        if self.buffers.is_empty() {
+            self.buffers.push(Buffer::new("Untitled 1".to_string()));
+        }
+        self.current_buffer_idx = self.current_buffer_idx.min(self.buffers.len() - 1);
+        self.restore_buffer_cursor();
+        self.show_completions = false;
+        self.completions.clear();
+        self.pending_cursor = None;
+        self.save_state();
+
+        // A bug that fires every frame shouldn't flood the console
+        if self.last_panic.as_deref() != Some(message.as_str()) {
+            self.console.push(ConsoleLine::note(format!(
+                "internal error, your work was saved: {}",
+                message
+            )));
+            self.last_panic = Some(message);
+        }
+    }
+}
+
+impl eframe::App for SapfAsPlainText {
+    fn clear_color(&self, visuals: &egui::Visuals) -> [f32; 4] {
+        if self.settings.transparent {
+            egui::Rgba::TRANSPARENT.to_array()
+        } else {
+            visuals.window_fill().to_normalized_gamma_f32()
+        }
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.save_state();
+
+        // Without it sapf is left to go away on its own and keeps sounding until it does
+        if !self.settings.stop_on_exit {
+            return;
+        }
+
+        let mut commands = Vec::new();
+        if !self.settings.dry_run {
+            commands.push(self.settings.stop_command.clone());
+            commands.push(self.settings.exit_command.clone());
+            commands.retain(|command| !command.trim().is_empty());
+        }
+        for connection in self.connections.drain(..) {
+            connection.shutdown(&commands);
+        }
+    }
+
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let frame = panic::catch_unwind(AssertUnwindSafe(|| self.update_frame(ctx)));
+        if let Err(payload) = frame {
+            self.recover_from_panic(payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID_STATE: &str = r#"{
+        "version": 1,
+        "buffers": [{"content": "backup", "cursor_pos": 0, "name": "a", "is_modified": false, "file_path": null}],
+        "current_buffer_idx": 0,
+        "next_buffer_id": 1
+    }"#;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sapf-apt-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn save_buffer(state_path: &Path, content: &str) {
+        let mut buffer = Buffer::new("a".to_string());
+        buffer.content = content.to_string();
+        let state = AppStateRef {
+            version: STATE_VERSION,
+            buffers: std::slice::from_ref(&buffer),
+            current_buffer_idx: 0,
+            next_buffer_id: 1,
+            settings: &Settings::default(),
+            set_list: &[],
+            eval_history: &[],
+            window: None,
+            keybindings: &BTreeMap::new(),
+        };
+        state.save_to_path(state_path).unwrap();
+    }
+
+    fn saved_content(path: &Path) -> String {
+        let state: AppState = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        state.buffers[0].content.clone()
+    }
+
+    #[test]
+    fn load_recovers_the_backup_when_the_state_is_garbage() {
+        let dir = test_dir("garbage");
+        let state_path = dir.join("state.json");
+        fs::write(&state_path, "{ not json").unwrap();
+        fs::write(sibling_path(&state_path, "bak"), VALID_STATE).unwrap();
+
+        let state = AppState::load_from_path(&state_path).unwrap();
+        assert_eq!(state.buffers.len(), 1);
+        assert_eq!(state.buffers[0].content, "backup");
+    }
+
+    #[test]
+    fn backup_skips_a_state_that_does_not_load() {
+        let dir = test_dir("skip-backup");
+        let state_path = dir.join("state.json");
+        fs::write(&state_path, "{ not json").unwrap();
+
+        save_buffer(&state_path, "fresh");
+        assert!(!sibling_path(&state_path, "bak").exists());
+        assert_eq!(saved_content(&state_path), "fresh");
+    }
+
+    #[test]
+    fn backup_is_not_refreshed_on_every_save() {
+        let dir = test_dir("throttle-backup");
+        let state_path = dir.join("state.json");
+        save_buffer(&state_path, "first");
+        save_buffer(&state_path, "second");
+        save_buffer(&state_path, "third");
+
+        assert_eq!(saved_content(&sibling_path(&state_path, "bak")), "first");
+        assert_eq!(saved_content(&state_path), "third");
+    }
+
+    #[test]
+    fn rename_accepts_only_plain_file_names() {
+        assert!(is_plain_file_name("drums.sapf"));
+        assert!(is_plain_file_name("bass line"));
+        assert!(!is_plain_file_name("../x.sapf"));
+        assert!(!is_plain_file_name("sub/x.sapf"));
+        assert!(!is_plain_file_name("sub\\x.sapf"));
+        assert!(!is_plain_file_name(".."));
+        assert!(!is_plain_file_name("/x.sapf"));
+    }
+
+    #[test]
+    fn saved_cursor_is_clamped_when_the_content_shrank() {
+        // Saved with the cursor at the end of a longer buffer, the content since cut down
+        let json = r#"{"content": "sin🎵", "cursor_pos": 40, "name": "a", "is_modified": false, "file_path": null}"#;
+        let mut buffer: Buffer = serde_json::from_str(json).unwrap();
+        assert_eq!(buffer.clamp_cursor(), 4);
+        assert_eq!(buffer.cursor_pos, 4);
+
+        // A cursor that still fits stays where it was
+        buffer.cursor_pos = 2;
+        assert_eq!(buffer.clamp_cursor(), 2);
+    }
+
+    #[test]
+    fn unversioned_state_loads_with_defaults_and_keeps_the_original() {
+        let dir = test_dir("unversioned");
+        let state_path = dir.join("state.json");
+        let unversioned = r#"{
+            "buffers": [{"content": "old", "cursor_pos": 1, "name": "a", "is_modified": true, "file_path": null}],
+            "current_buffer_idx": 0,
+            "next_buffer_id": 1
+        }"#;
+        fs::write(&state_path, unversioned).unwrap();
+
+        let state = AppState::load_from_path(&state_path).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert!(state.load_warning.is_none());
+        assert_eq!(state.buffers[0].content, "old");
+        assert!(!state.buffers[0].locked);
+        assert!(state.buffers[0].armed_lines.is_empty());
+        assert!(state.set_list.is_empty());
+        assert!(state.eval_history.is_empty());
+        assert!(state.window.is_none());
+        assert!(state.keybindings.is_empty());
+
+        let copy = fs::read_to_string(sibling_path(&state_path, "v0")).unwrap();
+        assert_eq!(copy, unversioned);
+    }
+
+    #[test]
+    fn newer_state_loads_with_a_warning() {
+        let dir = test_dir("newer");
+        let state_path = dir.join("state.json");
+        let newer = VALID_STATE.replace("\"version\": 1", "\"version\": 99");
+        fs::write(&state_path, &newer).unwrap();
+
+        let state = AppState::load_from_path(&state_path).unwrap();
+        assert!(state.load_warning.is_some());
+        assert_eq!(
+            fs::read_to_string(sibling_path(&state_path, "v99")).unwrap(),
+            newer
+        );
     }
 }
@@ -0,0 +1,83 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+pub struct LoadedFile {
+    pub path: PathBuf,
+    pub bytes: Result<Vec<u8>, String>,
+}
+
+// Files read one after another on a background thread, picked up by the UI as they arrive
+pub struct FileBatch {
+    receiver: Receiver<LoadedFile>,
+    pub total: usize,
+    pub done: usize,
+    pub current: String,
+    pub opened: usize,
+    pub skipped: Vec<String>,
+}
+
+impl FileBatch {
+    pub fn start(paths: Vec<PathBuf>) -> Self {
+        let total = paths.len();
+        let current = paths
+            .first()
+            .map(|path| file_name(path))
+            .unwrap_or_default();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for path in paths {
+                let bytes = fs::read(&path).map_err(|e| e.to_string());
+                if sender.send(LoadedFile { path, bytes }).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            receiver,
+            total,
+            done: 0,
+            current,
+            opened: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    pub fn poll(&mut self) -> Vec<LoadedFile> {
+        let loaded: Vec<LoadedFile> = self.receiver.try_iter().collect();
+        self.done += loaded.len();
+        if let Some(last) = loaded.last() {
+            self.current = file_name(&last.path);
+        }
+        loaded
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.done >= self.total
+    }
+
+    pub fn summary(&self) -> String {
+        if self.skipped.is_empty() {
+            format!("opened {} files", self.opened)
+        } else {
+            format!(
+                "opened {} of {} files, skipped {}",
+                self.opened,
+                self.total,
+                self.skipped.join(", ")
+            )
+        }
+    }
+}
+
+pub fn file_name(path: &std::path::Path) -> String {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("Untitled")
+        .to_string()
+}
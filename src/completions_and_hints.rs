@@ -1,6 +1,10 @@
+use crate::dict::VALUES_JSON;
+use crate::literals::{Literal, builtin_literals, load_user_literals};
+use crate::log;
+use crate::tokenizer::{TokenKind, tokenize};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use crate::dict::VALUES_JSON;
+use std::ops::Range;
 
 //TODO
 
@@ -10,15 +14,36 @@ pub struct CategoryData {
     pub items: HashMap<String, String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CompletionKind {
+    Category,
+    Keyword,
+    UserDefined,
+    Literal,
+}
+
+impl CompletionKind {
+    pub fn icon(self) -> &'static str {
+        match self {
+            CompletionKind::Category => "▸",
+            CompletionKind::Keyword => "•",
+            CompletionKind::UserDefined => "=",
+            CompletionKind::Literal => "#",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CompletionItem {
     pub label: String,
     pub documentation: String,
+    pub kind: CompletionKind,
 }
 
 pub struct SapfDictionary {
     categories: HashMap<String, CategoryData>,
     all_keywords: HashMap<String, String>,
+    literals: Vec<Literal>,
 }
 
 impl SapfDictionary {
@@ -26,9 +51,16 @@ impl SapfDictionary {
         let categories = load_categories();
         let all_keywords = Self::build_all_keywords(&categories);
 
+        let mut literals = builtin_literals();
+        match load_user_literals() {
+            Ok(user_literals) => literals.extend(user_literals),
+            Err(e) => log::error!("Failed to load literals: {}", e),
+        }
+
         Self {
             categories,
             all_keywords,
+            literals,
         }
     }
 
@@ -42,44 +74,146 @@ impl SapfDictionary {
         all_keywords
     }
 
-    pub fn get_completions(&self, current_input: &str) -> Vec<CompletionItem> {
-        let mut items = Vec::new();
+    // `user_words` are the words defined in the current buffer, see `defined_words`.
+    // Prefix matches come first in their usual order, then fuzzy ones best first
+    pub fn get_completions(
+        &self,
+        current_input: &str,
+        user_words: &[String],
+    ) -> Vec<CompletionItem> {
+        let mut items: Vec<(MatchRank, CompletionItem)> = Vec::new();
 
         if let Some((category_prefix, item_prefix)) = current_input.split_once('.') {
             if let Some(category) = self.categories.get(category_prefix) {
-                items.extend(
-                    category
-                        .items
-                        .iter()
-                        .filter(|(k, _)| k.starts_with(item_prefix.trim()))
-                        .map(|(k, d)| CompletionItem {
+                let item_prefix = item_prefix.trim();
+                items.extend(category.items.iter().filter_map(|(k, d)| {
+                    let rank = match_rank(item_prefix, k)?;
+                    Some((
+                        rank,
+                        CompletionItem {
                             label: k.clone(),
                             documentation: d.clone(),
-                        }),
-                );
+                            kind: CompletionKind::Keyword,
+                        },
+                    ))
+                }));
             }
         } else {
-            for (category_name, category_data) in &self.categories {
-                if category_name.starts_with(current_input) {
-                    items.push(CompletionItem {
-                        label: format!("{}.", category_name),
-                        documentation: category_data.description.clone(),
-                    });
-                }
-            }
+            items.extend(self.categories.iter().filter_map(|(name, data)| {
+                let rank = match_rank(current_input, name)?;
+                Some((
+                    rank,
+                    CompletionItem {
+                        label: format!("{}.", name),
+                        documentation: data.description.clone(),
+                        kind: CompletionKind::Category,
+                    },
+                ))
+            }));
 
-            items.extend(
-                self.all_keywords
-                    .iter()
-                    .filter(|(k, _)| k.starts_with(current_input))
-                    .map(|(k, d)| CompletionItem {
+            items.extend(self.all_keywords.iter().filter_map(|(k, d)| {
+                let rank = match_rank(current_input, k)?;
+                Some((
+                    rank,
+                    CompletionItem {
                         label: k.clone(),
                         documentation: d.clone(),
+                        kind: CompletionKind::Keyword,
+                    },
+                ))
+            }));
+
+            items.extend(
+                user_words
+                    .iter()
+                    .filter(|word| !self.all_keywords.contains_key(*word))
+                    .filter_map(|word| {
+                        let rank = match_rank(current_input, word)?;
+                        Some((
+                            rank,
+                            CompletionItem {
+                                label: word.clone(),
+                                documentation: "defined in this buffer".to_string(),
+                                kind: CompletionKind::UserDefined,
+                            },
+                        ))
                     }),
             );
         }
 
-        items
+        items.sort_by(|(a_rank, a), (b_rank, b)| {
+            a_rank
+                .cmp(b_rank)
+                .then_with(|| a.kind.cmp(&b.kind))
+                .then_with(|| a.label.cmp(&b.label))
+        });
+        items.into_iter().map(|(_, item)| item).collect()
+    }
+
+    // Values like note frequencies or sample rates, keyed by what's being typed
+    pub fn get_literal_completions(&self, current_input: &str) -> Vec<CompletionItem> {
+        if current_input.len() < 2 {
+            return Vec::new();
+        }
+
+        self.literals
+            .iter()
+            .filter(|literal| literal.key.starts_with(current_input))
+            .map(|literal| CompletionItem {
+                label: literal.value.clone(),
+                documentation: literal.description.clone(),
+                kind: CompletionKind::Literal,
+            })
+            .collect()
+    }
+
+    pub fn search_docs(&self, query: &str) -> Vec<CompletionItem> {
+        let query = query.trim().to_lowercase();
+        let terms: Vec<&str> = query.split_whitespace().collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let categories = self.categories.iter().map(|(name, category)| {
+            (
+                format!("{}.", name),
+                &category.description,
+                CompletionKind::Category,
+            )
+        });
+        let keywords = self
+            .all_keywords
+            .iter()
+            .map(|(keyword, doc)| (keyword.clone(), doc, CompletionKind::Keyword));
+
+        let mut scored: Vec<(i32, CompletionItem)> = categories
+            .chain(keywords)
+            .filter_map(|(label, documentation, kind)| {
+                let haystack = format!("{} {}", label, documentation).to_lowercase();
+                if !terms.iter().all(|term| haystack.contains(term)) {
+                    return None;
+                }
+
+                let score = fuzzy_score(&query, &label).unwrap_or(0);
+                Some((
+                    score,
+                    CompletionItem {
+                        label,
+                        documentation: documentation.clone(),
+                        kind,
+                    },
+                ))
+            })
+            .collect();
+
+        scored.sort_by(|(a_score, a), (b_score, b)| {
+            b_score.cmp(a_score).then_with(|| a.label.cmp(&b.label))
+        });
+        scored.into_iter().map(|(_, item)| item).collect()
+    }
+
+    pub fn is_keyword(&self, word: &str) -> bool {
+        self.all_keywords.contains_key(word)
     }
 
     pub fn get_hover_info(&self, word: &str) -> Option<String> {
@@ -146,6 +280,244 @@ pub fn get_current_word_for_completion(text: &str, cursor_pos: usize) -> Option<
     }
 }
 
+// Byte range a completion replaces: the word before the cursor and, with
+// `consume_rest`, the rest of it after the cursor too
+pub fn completion_range(
+    text: &str,
+    cursor_pos: usize,
+    consume_rest: bool,
+) -> std::ops::Range<usize> {
+    let bytes = text.as_bytes();
+    let cursor_pos = cursor_pos.min(text.len());
+    let is_completion_char = |c: u8| is_word_char(c) || c == b'.';
+
+    let mut start = cursor_pos;
+    while start > 0 && is_completion_char(bytes[start - 1]) {
+        start -= 1;
+    }
+
+    let mut end = cursor_pos;
+    if consume_rest {
+        while end < bytes.len() && is_word_char(bytes[end]) {
+            end += 1;
+        }
+    }
+
+    start..end
+}
+
+// Words bound with `= name` in sapf code, sorted and deduplicated
+pub fn defined_words(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = text
+        .lines()
+        .map(|line| line.split(';').next().unwrap_or(""))
+        .flat_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            tokens
+                .windows(2)
+                .filter(|pair| pair[0] == "=")
+                .map(|pair| pair[1].to_string())
+                .collect::<Vec<_>>()
+        })
+        .filter(|word| word.bytes().all(is_word_char))
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+// Byte offsets of each `= word` binding, skipping comments and strings
+pub fn find_definitions(text: &str, word: &str) -> Vec<usize> {
+    let tokens: Vec<_> = tokenize(text)
+        .into_iter()
+        .filter(|token| !matches!(token.kind, TokenKind::Whitespace | TokenKind::Newline))
+        .collect();
+
+    tokens
+        .windows(2)
+        .filter(|pair| {
+            pair[0].kind == TokenKind::Word
+                && pair[0].text == "="
+                && pair[1].kind == TokenKind::Word
+                && pair[1].text == word
+        })
+        .map(|pair| pair[1].start)
+        .collect()
+}
+
+// `(freq phase --> out)` at the start of the docs becomes `${freq} ${phase} sinosc`,
+// arguments go first in sapf. Single letter inputs like `a b` are left out, those are
+// stack shuffles of whatever is already there
+pub fn usage_snippet(word: &str, documentation: &str) -> Option<String> {
+    let mut doc = documentation.trim_start();
+    if doc.starts_with('@') {
+        doc = doc.split_once(' ')?.1.trim_start();
+    }
+    let signature = doc.strip_prefix('(')?.split_once(')')?.0;
+    let (inputs, _) = signature.split_once("-->")?;
+
+    let inputs: Vec<&str> = inputs.split_whitespace().collect();
+    let plain_names = inputs.iter().all(|name| {
+        name.chars().count() > 1 && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+    });
+    if inputs.is_empty() || !plain_names {
+        return None;
+    }
+
+    let placeholders: Vec<String> = inputs.iter().map(|name| format!("${{{}}}", name)).collect();
+    Some(format!("{} {}", placeholders.join(" "), word))
+}
+
+// The snippet's text with the placeholders' names in place, and their char ranges in it
+pub fn expand_snippet(template: &str) -> (String, Vec<Range<usize>>) {
+    let mut text = String::new();
+    let mut placeholders = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        text.push_str(&rest[..start]);
+        let name = &rest[start + 2..start + len];
+        let name_start = text.chars().count();
+        text.push_str(name);
+        placeholders.push(name_start..name_start + name.chars().count());
+        rest = &rest[start + len + 1..];
+    }
+    text.push_str(rest);
+
+    (text, placeholders)
+}
+
+// Orders completions: prefix matches, then fuzzy matches by descending score
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum MatchRank {
+    Prefix,
+    Fuzzy(std::cmp::Reverse<i32>),
+}
+
+fn match_rank(input: &str, label: &str) -> Option<MatchRank> {
+    if label.starts_with(input) {
+        return Some(MatchRank::Prefix);
+    }
+    fuzzy_score(input, label).map(|score| MatchRank::Fuzzy(std::cmp::Reverse(score)))
+}
+
+// Subsequence match, rewarding consecutive characters and word starts, penalizing gaps
+pub fn fuzzy_score(pattern: &str, candidate: &str) -> Option<i32> {
+    let candidate: Vec<char> = candidate.chars().collect();
+    let mut score = 0;
+    let mut next_idx = 0;
+    let mut previous_match: Option<usize> = None;
+
+    for p in pattern.chars() {
+        let found = (next_idx..candidate.len())
+            .find(|&i| candidate[i].to_lowercase().eq(p.to_lowercase()))?;
+
+        score += 1;
+        if is_word_start(&candidate, found) {
+            score += 8;
+        }
+        match previous_match {
+            Some(previous) if previous + 1 == found => score += 5,
+            Some(previous) => score -= (found - previous - 1).min(3) as i32,
+            None => score -= found.min(3) as i32,
+        }
+
+        previous_match = Some(found);
+        next_idx = found + 1;
+    }
+
+    Some(score)
+}
+
+fn is_word_start(chars: &[char], idx: usize) -> bool {
+    idx == 0
+        || !chars[idx - 1].is_alphanumeric()
+        || (chars[idx].is_uppercase() && chars[idx - 1].is_lowercase())
+}
+
 fn is_word_char(c: u8) -> bool {
     c.is_ascii_alphanumeric() || c == b'_'
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completion_range_mid_word() {
+        let text = "440 sinwXXX .3 *";
+        let cursor_pos = "440 sin".len();
+        let word = "440 ".len().."440 sinwXXX".len();
+
+        // Consuming the rest replaces the whole word, `sinosc` leaves no `wXXX` behind
+        assert_eq!(completion_range(text, cursor_pos, true), word);
+        // Otherwise only the part before the cursor is replaced
+        assert_eq!(
+            completion_range(text, cursor_pos, false),
+            word.start..cursor_pos
+        );
+    }
+
+    #[test]
+    fn words_at_byte_positions_after_multibyte_text() {
+        let text = "; é🎵\nsinosc";
+        let cursor_byte = text.len() - "osc".len();
+        assert_eq!(
+            get_current_word_for_completion(text, cursor_byte),
+            Some("sin".to_string())
+        );
+        assert_eq!(
+            get_word_at_cursor(text, cursor_byte),
+            Some(("sinosc".to_string(), cursor_byte - 3, text.len()))
+        );
+    }
+
+    #[test]
+    fn expand_snippet_gives_placeholder_char_ranges() {
+        let (text, placeholders) = expand_snippet("${freq} ${phase} sinosc");
+        assert_eq!(text, "freq phase sinosc");
+        assert_eq!(placeholders, vec![0..4, 5..10]);
+    }
+
+    #[test]
+    fn expand_snippet_without_placeholders() {
+        let (text, placeholders) = expand_snippet("sinosc play");
+        assert_eq!(text, "sinosc play");
+        assert!(placeholders.is_empty());
+    }
+
+    #[test]
+    fn expand_snippet_counts_chars_before_a_placeholder() {
+        let (text, placeholders) = expand_snippet("é🎵 ${fréq} sinosc");
+        assert_eq!(text, "é🎵 fréq sinosc");
+        assert_eq!(placeholders, vec![3..7]);
+        let chars: Vec<char> = text.chars().collect();
+        assert_eq!(chars[3..7].iter().collect::<String>(), "fréq");
+    }
+
+    #[test]
+    fn usage_snippet_puts_the_arguments_first() {
+        assert_eq!(
+            usage_snippet("sinosc", "(freq phase --> out) a sine wave oscillator"),
+            Some("${freq} ${phase} sinosc".to_string())
+        );
+        // A leading tag is skipped
+        assert_eq!(
+            usage_snippet("lfsaw", "@ugen (freq phase --> out) a sawtooth"),
+            Some("${freq} ${phase} lfsaw".to_string())
+        );
+    }
+
+    #[test]
+    fn usage_snippet_skips_stack_shuffles_and_missing_signatures() {
+        assert_eq!(
+            usage_snippet("swap", "(a b --> b a) swaps the top two"),
+            None
+        );
+        assert_eq!(usage_snippet("pi", "(--> pi) the constant"), None);
+        assert_eq!(usage_snippet("play", "plays the signal"), None);
+    }
+}
@@ -0,0 +1,114 @@
+use eframe::egui::{Color32, RichText, Ui};
+
+// sapf writes its diagnostics to the same terminal as everything else, so
+// they're told apart by what they say
+const ERROR_MARKERS: &[&str] = &[
+    "error",
+    "undefined",
+    "underflow",
+    "overflow",
+    "not found",
+    "failed",
+];
+const WARNING_MARKERS: &[&str] = &["warning"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineKind {
+    Output,
+    Sent,
+    Note,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConsoleLine {
+    pub kind: LineKind,
+    pub text: String,
+    // Name of the sapf connection the line belongs to, when there are several
+    pub source: Option<String>,
+    // Identical consecutive output folded into this line
+    pub repeat: usize,
+}
+
+impl ConsoleLine {
+    pub fn output(text: String) -> Self {
+        Self {
+            kind: LineKind::Output,
+            text,
+            source: None,
+            repeat: 1,
+        }
+    }
+
+    pub fn sent(text: String) -> Self {
+        Self {
+            kind: LineKind::Sent,
+            text,
+            source: None,
+            repeat: 1,
+        }
+    }
+
+    pub fn note(text: String) -> Self {
+        Self {
+            kind: LineKind::Note,
+            text,
+            source: None,
+            repeat: 1,
+        }
+    }
+
+    pub fn with_source(mut self, source: Option<&str>) -> Self {
+        self.source = source.map(str::to_string);
+        self
+    }
+
+    pub fn is_repeat_of(&self, other: &ConsoleLine) -> bool {
+        self.kind == other.kind && self.source == other.source && self.text == other.text
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.kind == LineKind::Output && contains_marker(&self.text, ERROR_MARKERS)
+    }
+
+    pub fn is_warning(&self) -> bool {
+        self.kind == LineKind::Output && contains_marker(&self.text, WARNING_MARKERS)
+    }
+
+    pub fn rich_text(&self, ui: &Ui, note_color: Color32) -> RichText {
+        let mut text = match &self.source {
+            Some(source) => format!("[{}] {}", source, self.text),
+            None => self.text.clone(),
+        };
+        if self.repeat > 1 {
+            text.push_str(&format!(" (×{})", self.repeat));
+        }
+
+        match self.kind {
+            LineKind::Output if self.is_error() => {
+                RichText::new(text).color(ui.visuals().error_fg_color)
+            }
+            LineKind::Output if self.is_warning() => {
+                RichText::new(text).color(ui.visuals().warn_fg_color)
+            }
+            LineKind::Output => RichText::new(text),
+            LineKind::Sent => RichText::new(format!("> {}", text))
+                .color(ui.visuals().weak_text_color())
+                .italics(),
+            LineKind::Note => RichText::new(text).color(note_color),
+        }
+    }
+}
+
+fn contains_marker(text: &str, markers: &[&str]) -> bool {
+    let text = text.to_lowercase();
+    markers.iter().any(|marker| text.contains(marker))
+}
+
+// The quoted word in an error message, e.g. `foo` in "undefined word 'foo'"
+pub fn error_subject(message: &str) -> Option<&str> {
+    let start = message.find(['\'', '"', '`'])?;
+    let quote = message[start..].chars().next()?;
+    let rest = &message[start + 1..];
+    let end = rest.find(quote)?;
+    Some(&rest[..end]).filter(|subject| !subject.trim().is_empty())
+}
@@ -0,0 +1,106 @@
+// Past this many lines left to compare after the common ends are trimmed, the
+// LCS table gets too big and the middle is shown as removed then added
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffKind {
+    Same,
+    Added,
+    Removed,
+}
+
+#[derive(Clone, Debug)]
+pub struct DiffLine {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+impl DiffLine {
+    fn new(kind: DiffKind, text: &str) -> Self {
+        Self {
+            kind,
+            text: text.to_string(),
+        }
+    }
+}
+
+// Line-level diff from `old` to `new` by longest common subsequence
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut diff: Vec<DiffLine> = old[..prefix]
+        .iter()
+        .map(|line| DiffLine::new(DiffKind::Same, line))
+        .collect();
+    if old_middle.len().saturating_mul(new_middle.len()) > MAX_DIFF_CELLS {
+        diff.extend(
+            old_middle
+                .iter()
+                .map(|line| DiffLine::new(DiffKind::Removed, line)),
+        );
+        diff.extend(
+            new_middle
+                .iter()
+                .map(|line| DiffLine::new(DiffKind::Added, line)),
+        );
+    } else {
+        diff_middle(old_middle, new_middle, &mut diff);
+    }
+    diff.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| DiffLine::new(DiffKind::Same, line)),
+    );
+    diff
+}
+
+fn diff_middle(old: &[&str], new: &[&str], diff: &mut Vec<DiffLine>) {
+    // lengths[i][j] is the LCS length of old[i..] and new[j..]
+    let width = new.len() + 1;
+    let mut lengths = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if old[i] == new[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(DiffLine::new(DiffKind::Same, old[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            diff.push(DiffLine::new(DiffKind::Removed, old[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::new(DiffKind::Added, new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(
+        old[i..]
+            .iter()
+            .map(|line| DiffLine::new(DiffKind::Removed, line)),
+    );
+    diff.extend(
+        new[j..]
+            .iter()
+            .map(|line| DiffLine::new(DiffKind::Added, line)),
+    );
+}
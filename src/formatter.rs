@@ -0,0 +1,65 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Runs `command` with `input` on stdin and returns its stdout, the command is
+// split on whitespace into the program and its arguments
+pub fn run_formatter(
+    command: &str,
+    input: &str,
+    timeout: Duration,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().ok_or("no formatter command set")?;
+
+    let mut child = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Feed and drain the pipes on their own threads so a chatty program can't block on us
+    let mut stdin = child.stdin.take().ok_or("formatter stdin unavailable")?;
+    let input = input.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("{} timed out after {:?}", program, timeout).into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let _ = writer.join();
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        return Err(format!("{} exited with {}: {}", program, status, stderr.trim()).into());
+    }
+
+    Ok(stdout)
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut output);
+        }
+        output
+    })
+}
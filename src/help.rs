@@ -0,0 +1,167 @@
+use eframe::egui::{self, Ui};
+
+use crate::keymap::Keymap;
+
+// Actions from the keymap, shown with whatever keys they're bound to
+const KEYBINDINGS: &[(&str, &str)] = &[
+    ("eval", "send the selection, or the current block or line"),
+    ("eval_again", "send the last evaluated code again"),
+    ("eval_paragraph", "send the paragraph around the cursor"),
+    (
+        "send_buffer",
+        "send the whole buffer, skipping blank and comment lines",
+    ),
+    ("send_word", "send the word under the cursor"),
+    (
+        "eval_line",
+        "stop previous sounds and send the current line",
+    ),
+    (
+        "eval_loop",
+        "send the current line wrapped in the loop template",
+    ),
+    ("arm_line", "arm or disarm the current line"),
+    ("fire_armed", "send all armed lines"),
+    ("fire_next_armed", "send the next armed line"),
+    ("toggle_console", "hide or show the console"),
+    ("clear_console", "clear the console"),
+    ("scratch", "send a one-off line that isn't kept anywhere"),
+    ("stop", "stop all sound"),
+    ("record", "record the current line to a file"),
+    ("format", "pipe the buffer through the formatter command"),
+    ("eval_region", "send the region around the cursor"),
+    ("clear_stack", "clear the stack"),
+    ("print_stack", "print the stack"),
+    ("go_to_line", "go to line"),
+    (
+        "history_older",
+        "put an older sent expression in place of the current line",
+    ),
+    (
+        "history_newer",
+        "put a newer sent expression in place of the current line",
+    ),
+    (
+        "toggle_comment",
+        "comment or uncomment the current or selected lines",
+    ),
+    ("undo", "undo, kept per buffer"),
+    ("redo", "redo"),
+    (
+        "completions",
+        "completions popup, arrows and Page Up/Down pick, Enter accepts, Escape closes",
+    ),
+    ("new_buffer", "new buffer"),
+    ("close_buffer", "close buffer"),
+    ("open", "load a file into a new buffer"),
+    ("save", "save the buffer to its file"),
+    ("save_as", "save the buffer as a new file"),
+    ("insert_timestamp", "insert a timestamp comment"),
+    ("lock_buffer", "lock/unlock the buffer"),
+    ("live_eval", "toggle live eval for the buffer"),
+    (
+        "reveal_buffer",
+        "reveal the buffer's file in the file manager",
+    ),
+    ("search", "search all buffers"),
+    ("docs_search", "search the documentation"),
+    ("new_session", "new session"),
+    ("always_on_top", "toggle always on top"),
+    ("patterns", "show/hide patterns"),
+    ("set_list", "show/hide the set list"),
+    ("toggle_theme", "switch light/dark theme"),
+    ("pin_buffer_bar", "keep the buffer bar open"),
+    ("settings", "settings"),
+    ("next_buffer", "next buffer"),
+    ("prev_buffer", "previous buffer"),
+    ("next_set_list_entry", "next set list entry"),
+    ("prev_set_list_entry", "previous set list entry"),
+    ("select_word", "select the sapf word under the cursor"),
+    (
+        "select_block",
+        "select the block of non-blank lines around the cursor",
+    ),
+    ("transpose_line", "swap the current line with the one above"),
+    ("normalize", "normalize whitespace in the buffer"),
+    ("help", "this help"),
+    (
+        "rename_buffer",
+        "rename the buffer, double-clicking its tab does too",
+    ),
+    ("regions", "list regions to jump to or send"),
+    (
+        "cycle_focus",
+        "move focus between the editor and the console",
+    ),
+    ("dry_run", "toggle dry run"),
+    (
+        "go_to_definition",
+        "jump to the definition of the word under the cursor",
+    ),
+];
+
+// Keys the keymap doesn't cover
+const FIXED_KEYBINDINGS: &[(&str, &str)] = &[
+    ("Ctrl+Shift+V", "send the clipboard without pasting it"),
+    ("Ctrl+wheel", "change the editor font size"),
+];
+
+fn overview(keymap: &Keymap) -> String {
+    format!(
+        "\
+Code goes to sapf line by line. {eval} sends the selected text, otherwise the \
+innermost bracketed block around the cursor, or the current line when there is none. {stop} stops all sound, \
+{eval_line} stops and sends in one go.
+
+{record} sends the current line followed by `record`, naming the file after the \
+buffer and the time.
+
+{completions} completes sapf words, categories (`cat.`), words defined in the buffer \
+and note or sample rate literals. Words with documented arguments come with them \
+as placeholders, Tab jumps to the next one. Hovering a word shows its \
+documentation below the editor.",
+        eval = keymap.label("eval"),
+        stop = keymap.label("stop"),
+        eval_line = keymap.label("eval_line"),
+        record = keymap.label("record"),
+        completions = keymap.label("completions"),
+    )
+}
+
+pub fn help_ui(ui: &mut Ui, keymap: &Keymap) {
+    ui.label(overview(keymap));
+    ui.add_space(8.0);
+
+    egui::ScrollArea::vertical()
+        .max_height(360.0)
+        .show(ui, |ui| {
+            egui::Grid::new("keybindings")
+                .num_columns(2)
+                .striped(true)
+                .show(ui, |ui| {
+                    for (action, description) in KEYBINDINGS {
+                        ui.monospace(keymap.label(action));
+                        ui.label(*description);
+                        ui.end_row();
+                    }
+                    for (keys, description) in FIXED_KEYBINDINGS {
+                        ui.monospace(*keys);
+                        ui.label(*description);
+                        ui.end_row();
+                    }
+                });
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_listed_action_has_a_key() {
+        let keymap = Keymap::default();
+        for (action, _) in KEYBINDINGS {
+            assert!(!keymap.label(action).is_empty(), "no key for {}", action);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use eframe::egui::{InputState, Key, Modifiers};
+
+const NONE: u8 = 0;
+const CTRL: u8 = 1;
+const SHIFT: u8 = 2;
+const ALT: u8 = 4;
+
+// Action names as they appear in the state file's `keybindings`
+const DEFAULT_BINDINGS: &[(&str, u8, Key)] = &[
+    ("eval", CTRL, Key::Enter),
+    ("eval_again", CTRL | SHIFT, Key::Enter),
+    ("eval_paragraph", CTRL | ALT, Key::Enter),
+    ("send_buffer", CTRL | SHIFT, Key::B),
+    ("send_word", ALT, Key::Enter),
+    ("eval_line", CTRL, Key::E),
+    ("eval_loop", CTRL, Key::L),
+    ("eval_region", CTRL | ALT, Key::R),
+    ("stop", CTRL, Key::Period),
+    ("record", CTRL, Key::R),
+    ("clear_stack", CTRL, Key::D),
+    ("print_stack", CTRL, Key::P),
+    ("arm_line", CTRL, Key::M),
+    ("fire_armed", CTRL | SHIFT, Key::M),
+    ("fire_next_armed", CTRL | ALT, Key::M),
+    ("scratch", CTRL, Key::K),
+    ("toggle_console", CTRL, Key::J),
+    ("clear_console", CTRL | SHIFT, Key::K),
+    ("cycle_focus", NONE, Key::F6),
+    ("go_to_line", CTRL, Key::G),
+    ("go_to_definition", NONE, Key::F12),
+    ("regions", NONE, Key::F4),
+    ("history_older", CTRL, Key::ArrowUp),
+    ("history_newer", CTRL, Key::ArrowDown),
+    ("completions", CTRL, Key::Tab),
+    ("toggle_comment", CTRL, Key::Slash),
+    ("undo", CTRL, Key::Z),
+    ("redo", CTRL | SHIFT, Key::Z),
+    ("transpose_line", ALT, Key::T),
+    ("select_word", ALT, Key::W),
+    ("select_block", ALT, Key::B),
+    ("normalize", ALT | SHIFT, Key::F),
+    ("format", CTRL | ALT, Key::F),
+    ("insert_timestamp", CTRL | ALT, Key::T),
+    ("new_buffer", CTRL, Key::T),
+    ("close_buffer", CTRL, Key::W),
+    ("rename_buffer", NONE, Key::F2),
+    ("open", CTRL, Key::O),
+    ("save", CTRL, Key::S),
+    ("save_as", CTRL | SHIFT, Key::S),
+    ("next_buffer", ALT, Key::Tab),
+    ("prev_buffer", ALT | SHIFT, Key::Tab),
+    ("next_set_list_entry", ALT, Key::PageDown),
+    ("prev_set_list_entry", ALT, Key::PageUp),
+    ("lock_buffer", CTRL | ALT, Key::L),
+    ("live_eval", CTRL | ALT, Key::E),
+    ("reveal_buffer", CTRL | ALT, Key::O),
+    ("search", CTRL | SHIFT, Key::F),
+    ("docs_search", CTRL | SHIFT, Key::D),
+    ("new_session", CTRL | SHIFT, Key::N),
+    ("always_on_top", CTRL | SHIFT, Key::A),
+    ("patterns", CTRL | SHIFT, Key::P),
+    ("set_list", CTRL | SHIFT, Key::L),
+    ("toggle_theme", CTRL | SHIFT, Key::T),
+    ("pin_buffer_bar", CTRL, Key::B),
+    ("settings", CTRL, Key::Comma),
+    ("help", NONE, Key::F1),
+    ("dry_run", NONE, Key::F8),
+];
+
+// A key and exactly the modifiers held with it, written like "Ctrl+Shift+Enter"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyCombo {
+    ctrl: bool,
+    shift: bool,
+    alt: bool,
+    key: Key,
+}
+
+impl KeyCombo {
+    fn new(modifiers: u8, key: Key) -> Self {
+        Self {
+            ctrl: modifiers & CTRL != 0,
+            shift: modifiers & SHIFT != 0,
+            alt: modifiers & ALT != 0,
+            key,
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        let key_name = parts.pop()?;
+        let key = Key::from_name(key_name).or_else(|| Key::from_name(&key_name.to_uppercase()))?;
+
+        let mut combo = Self::new(NONE, key);
+        for part in parts {
+            match part.to_lowercase().as_str() {
+                "ctrl" | "control" => combo.ctrl = true,
+                "shift" => combo.shift = true,
+                "alt" | "option" => combo.alt = true,
+                _ => return None,
+            }
+        }
+        Some(combo)
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            alt: self.alt,
+            ctrl: self.ctrl,
+            shift: self.shift,
+            ..Default::default()
+        }
+    }
+
+    fn pressed(&self, i: &InputState) -> bool {
+        i.key_pressed(self.key)
+            && i.modifiers.ctrl == self.ctrl
+            && i.modifiers.shift == self.shift
+            && i.modifiers.alt == self.alt
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        write!(f, "{}", self.key.name())
+    }
+}
+
+pub struct Keymap {
+    combos: HashMap<&'static str, KeyCombo>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            combos: DEFAULT_BINDINGS
+                .iter()
+                .map(|&(action, modifiers, key)| (action, KeyCombo::new(modifiers, key)))
+                .collect(),
+        }
+    }
+}
+
+impl Keymap {
+    // The defaults with whatever `bindings` overrides, plus a warning for every
+    // entry that couldn't be used and so keeps its default
+    pub fn load(bindings: &BTreeMap<String, String>) -> (Self, Vec<String>) {
+        let mut keymap = Self::default();
+        let mut warnings = Vec::new();
+        for (action, text) in bindings {
+            let Some((&action, &combo)) = keymap.combos.get_key_value(action.as_str()) else {
+                warnings.push(format!("keybindings: unknown action \"{}\"", action));
+                continue;
+            };
+            match KeyCombo::parse(text) {
+                Some(parsed) => {
+                    keymap.combos.insert(action, parsed);
+                }
+                None => warnings.push(format!(
+                    "keybindings: \"{}\" for {} isn't a key, keeping {}",
+                    text, action, combo
+                )),
+            }
+        }
+        (keymap, warnings)
+    }
+
+    // Every action with its default, for filling in what the state file lacks
+    pub fn default_bindings() -> impl Iterator<Item = (&'static str, String)> {
+        DEFAULT_BINDINGS
+            .iter()
+            .map(|&(action, modifiers, key)| (action, KeyCombo::new(modifiers, key).to_string()))
+    }
+
+    // How the action's key reads in help and tooltips, empty for an unknown action
+    pub fn label(&self, action: &str) -> String {
+        self.combos
+            .get(action)
+            .map(ToString::to_string)
+            .unwrap_or_default()
+    }
+
+    pub fn pressed(&self, i: &InputState, action: &str) -> bool {
+        self.combos
+            .get(action)
+            .is_some_and(|combo| combo.pressed(i))
+    }
+
+    // For keys the editor would otherwise act on too
+    pub fn consume(&self, i: &mut InputState, action: &str) -> bool {
+        self.combos
+            .get(action)
+            .is_some_and(|combo| i.consume_key(combo.modifiers(), combo.key))
+    }
+}
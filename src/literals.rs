@@ -0,0 +1,72 @@
+use std::{fs, path::PathBuf};
+
+use crate::{patterns::parse_patterns, settings::get_config_dir};
+
+const LITERALS_FILE: &str = "literals.sapf";
+const NOTE_NAMES: [&str; 12] = [
+    "c", "cs", "d", "ds", "e", "f", "fs", "g", "gs", "a", "as", "b",
+];
+const SAMPLE_RATES: [u32; 5] = [22050, 44100, 48000, 88200, 96000];
+
+// A value offered for completion when its key is typed, e.g. `a4` -> `440.00`
+#[derive(Clone, Debug)]
+pub struct Literal {
+    pub key: String,
+    pub value: String,
+    pub description: String,
+}
+
+pub fn builtin_literals() -> Vec<Literal> {
+    let mut literals = Vec::new();
+
+    // Octave 0 and up, sharps are spelled `s` so the key stays a single word
+    for midi in 12..128u32 {
+        let key = format!("{}{}", NOTE_NAMES[(midi % 12) as usize], midi / 12 - 1);
+        let freq = 440.0 * 2f64.powf((midi as f64 - 69.0) / 12.0);
+        literals.push(Literal {
+            key: key.clone(),
+            value: format!("{:.2}", freq),
+            description: format!("{} in Hz", key),
+        });
+        literals.push(Literal {
+            key: key.clone(),
+            value: midi.to_string(),
+            description: format!("{} as a MIDI note", key),
+        });
+    }
+
+    for rate in SAMPLE_RATES {
+        literals.push(Literal {
+            key: "sr".to_string(),
+            value: rate.to_string(),
+            description: "sample rate".to_string(),
+        });
+    }
+
+    literals
+}
+
+pub fn get_literals_file_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = get_config_dir()?;
+    path.push(LITERALS_FILE);
+    Ok(path)
+}
+
+// Extra literals from `literals.sapf`, one `key = value` per line like the patterns file
+pub fn load_user_literals() -> Result<Vec<Literal>, Box<dyn std::error::Error>> {
+    let path = get_literals_file_path()?;
+
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let text = fs::read_to_string(path)?;
+    Ok(parse_patterns(&text)
+        .into_iter()
+        .map(|pattern| Literal {
+            key: pattern.name,
+            value: pattern.code,
+            description: format!("from {}", LITERALS_FILE),
+        })
+        .collect())
+}
@@ -1,28 +1,90 @@
 mod app;
+mod batch;
 mod completions_and_hints;
+mod console;
 mod dict;
-mod window;
+mod diff;
+mod formatter;
+mod help;
+mod keymap;
+mod literals;
+mod log;
+mod minimap;
+mod patterns;
+mod regions;
+mod sapf;
+mod search;
+mod settings;
+mod text;
+mod tokenizer;
 mod ui;
+mod undo;
+mod window;
+
+use std::path::PathBuf;
 
-use crate::app::SapfAsPlainText;
+use crate::app::{AppState, SapfAsPlainText, set_state_file_path};
 use eframe::egui::{self, Vec2, vec2};
 
 const WINDOW_SIZE: Vec2 = vec2(680.0, 840.0);
+const MIN_WINDOW_SIZE: Vec2 = vec2(400.0, 480.0);
 const WINDOW_TITLE: &str = "sapf as plain* text";
 
+// `--state <path>` or `--state=<path>` points at an alternate state file
+fn state_path_arg() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--state" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--state=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
 fn main() -> eframe::Result {
-    let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default()
-            .with_decorations(false)
-            .with_inner_size(WINDOW_SIZE)
-            .with_min_inner_size(WINDOW_SIZE)
-            .with_transparent(true),
+    if let Some(path) = state_path_arg() {
+        set_state_file_path(path);
+    }
+
+    // `--ephemeral` starts from a clean state and never writes one
+    let ephemeral = std::env::args().skip(1).any(|arg| arg == "--ephemeral");
+    let saved_state = if ephemeral {
+        None
+    } else {
+        AppState::load_from_file()
+            .inspect_err(|e| log::info!("Failed to load state: {}", e))
+            .ok()
+    };
+    let settings = saved_state
+        .as_ref()
+        .map(|state| state.settings.clone())
+        .unwrap_or_default();
+    log::set_level(settings.log_level);
 
+    let mut viewport = egui::ViewportBuilder::default()
+        .with_decorations(false)
+        .with_resizable(true)
+        .with_inner_size(WINDOW_SIZE)
+        .with_min_inner_size(MIN_WINDOW_SIZE)
+        .with_transparent(settings.transparent)
+        .with_window_level(settings.window_level());
+    // Kept on the screen by the app once it knows the monitor size
+    if let Some(geometry) = saved_state.as_ref().and_then(|state| state.window) {
+        viewport = viewport
+            .with_inner_size(geometry.size().max(MIN_WINDOW_SIZE))
+            .with_position(geometry.position());
+    }
+
+    let options = eframe::NativeOptions {
+        viewport,
         ..Default::default()
     };
     eframe::run_native(
         WINDOW_TITLE,
         options,
-        Box::new(|cc| Ok(Box::new(SapfAsPlainText::new(cc)))),
+        Box::new(move |cc| Ok(Box::new(SapfAsPlainText::new(cc, saved_state, ephemeral)))),
     )
 }
@@ -0,0 +1,91 @@
+use eframe::egui::{self, Sense, Ui};
+
+use crate::tokenizer::{TokenKind, tokenize};
+
+// Lines longer than this are drawn full width
+const MAX_LINE_CHARS: usize = 80;
+const MAX_LINE_HEIGHT: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LineOverview {
+    // Both as fractions of the minimap width
+    pub indent: f32,
+    pub width: f32,
+    pub has_keyword: bool,
+}
+
+pub fn line_overview(text: &str, is_keyword: impl Fn(&str) -> bool) -> Vec<LineOverview> {
+    let mut lines: Vec<LineOverview> = text
+        .split('\n')
+        .map(|line| {
+            let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+            let len = line.trim_end().chars().count().max(indent);
+            LineOverview {
+                indent: width_fraction(indent),
+                width: width_fraction(len) - width_fraction(indent),
+                has_keyword: false,
+            }
+        })
+        .collect();
+
+    let mut line = 0;
+    for token in tokenize(text) {
+        match token.kind {
+            TokenKind::Newline => line += 1,
+            TokenKind::Word if is_keyword(token.text) => lines[line].has_keyword = true,
+            _ => {}
+        }
+    }
+
+    lines
+}
+
+fn width_fraction(chars: usize) -> f32 {
+    chars.min(MAX_LINE_CHARS) as f32 / MAX_LINE_CHARS as f32
+}
+
+// Draws the overview with the visible part (as fractions of the buffer height)
+// highlighted, returning where it was clicked or dragged as such a fraction
+pub fn minimap_ui(ui: &mut Ui, lines: &[LineOverview], viewport: (f32, f32)) -> Option<f32> {
+    let (rect, response) = ui.allocate_exact_size(ui.available_size(), Sense::click_and_drag());
+    let painter = ui.painter_at(rect);
+    let line_height = (rect.height() / lines.len().max(1) as f32).min(MAX_LINE_HEIGHT);
+    let content_height = line_height * lines.len() as f32;
+
+    let keyword_color = ui.visuals().text_color().gamma_multiply(0.6);
+    let plain_color = ui.visuals().weak_text_color().gamma_multiply(0.6);
+    for (idx, line) in lines.iter().enumerate() {
+        if line.width <= 0.0 {
+            continue;
+        }
+
+        let min = egui::pos2(
+            rect.left() + line.indent * rect.width(),
+            rect.top() + idx as f32 * line_height,
+        );
+        let size = egui::vec2(line.width * rect.width(), line_height.max(1.0));
+        let color = if line.has_keyword {
+            keyword_color
+        } else {
+            plain_color
+        };
+        painter.rect_filled(egui::Rect::from_min_size(min, size), 0.0, color);
+    }
+
+    let (start, end) = viewport;
+    let visible = egui::Rect::from_x_y_ranges(
+        rect.x_range(),
+        rect.top() + start * content_height..=rect.top() + end * content_height,
+    );
+    painter.rect_filled(
+        visible,
+        2.0,
+        ui.visuals().selection.bg_fill.gamma_multiply(0.3),
+    );
+
+    if !(response.clicked() || response.dragged()) {
+        return None;
+    }
+    let pos = response.interact_pointer_pos()?;
+    Some(((pos.y - rect.top()) / content_height.max(1.0)).clamp(0.0, 1.0))
+}
@@ -0,0 +1,47 @@
+use std::{fs, path::PathBuf};
+
+use crate::settings::get_config_dir;
+
+const PATTERNS_FILE: &str = "patterns.sapf";
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pub name: String,
+    pub code: String,
+}
+
+pub fn get_patterns_file_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = get_config_dir()?;
+    path.push(PATTERNS_FILE);
+    Ok(path)
+}
+
+pub fn load_patterns() -> Result<Vec<Pattern>, Box<dyn std::error::Error>> {
+    let path = get_patterns_file_path()?;
+
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let text = fs::read_to_string(path)?;
+    Ok(parse_patterns(&text))
+}
+
+// One `name = code` definition per line, `;` comments and blank lines are skipped
+pub fn parse_patterns(text: &str) -> Vec<Pattern> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .filter_map(|line| {
+            let (name, code) = line.split_once('=')?;
+            let (name, code) = (name.trim(), code.trim());
+            if name.is_empty() || code.is_empty() {
+                return None;
+            }
+            Some(Pattern {
+                name: name.to_string(),
+                code: code.to_string(),
+            })
+        })
+        .collect()
+}
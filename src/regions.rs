@@ -0,0 +1,57 @@
+// A named part of a buffer between a start marker line and an end marker line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    // Line of the start marker, the body follows it
+    pub start_line: usize,
+    // Line of the end marker, or one past the last line if the region is never closed
+    pub end_line: usize,
+}
+
+impl Region {
+    pub fn contains_line(&self, line: usize) -> bool {
+        (self.start_line..=self.end_line).contains(&line)
+    }
+
+    pub fn body(&self, text: &str) -> String {
+        text.lines()
+            .skip(self.start_line + 1)
+            .take(self.end_line.saturating_sub(self.start_line + 1))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+// Regions don't nest, a start marker inside an open region starts a new one
+pub fn find_regions(text: &str, start_marker: &str, end_marker: &str) -> Vec<Region> {
+    if start_marker.trim().is_empty() || end_marker.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut regions = Vec::new();
+    let mut open: Option<(String, usize)> = None;
+    let mut line_count = 0;
+
+    for (idx, line) in text.lines().enumerate() {
+        line_count = idx + 1;
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix(start_marker) {
+            regions.extend(close_region(&mut open, idx));
+            open = Some((name.trim().to_string(), idx));
+        } else if line.starts_with(end_marker) {
+            regions.extend(close_region(&mut open, idx));
+        }
+    }
+
+    regions.extend(close_region(&mut open, line_count));
+    regions
+}
+
+fn close_region(open: &mut Option<(String, usize)>, end_line: usize) -> Option<Region> {
+    let (name, start_line) = open.take()?;
+    Some(Region {
+        name,
+        start_line,
+        end_line,
+    })
+}
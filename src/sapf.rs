@@ -0,0 +1,210 @@
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use portable_pty::{Child, CommandBuilder, PtySize, native_pty_system};
+
+use crate::{console::ConsoleLine, log, text::strip_ansi};
+
+pub const PRIMARY_CONNECTION: &str = "main";
+const MAX_SEND_QUEUE: usize = 4096;
+// How long sapf gets to act on the exit commands, and how long the reader gets to notice it's gone
+const EXIT_GRACE: Duration = Duration::from_millis(300);
+const READER_EXIT_TIMEOUT: Duration = Duration::from_millis(500);
+// Queued lines wait this long after spawning so sapf is ready to read them
+const STARTUP_DELAY: Duration = Duration::from_millis(1000);
+
+// One running sapf process and the queue of lines waiting to be written to it
+pub struct SapfConnection {
+    pub name: String,
+    sender: Sender<String>,
+    receiver: Receiver<ConsoleLine>,
+    send_queue: VecDeque<String>,
+    send_queue_total: usize,
+    next_send_at: Instant,
+    child: Box<dyn Child + Send + Sync>,
+    // Cleared by the reader thread when sapf's output ends, i.e. the process is gone
+    alive: Arc<AtomicBool>,
+    writer_thread: JoinHandle<()>,
+    reader_thread: JoinHandle<()>,
+}
+
+impl SapfConnection {
+    pub fn spawn(name: &str, program: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let pty = native_pty_system();
+
+        let pty_pair = pty.openpty(PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+
+        let cmd = CommandBuilder::new(program);
+
+        // not my terminology!
+        let child = pty_pair.slave.spawn_command(cmd)?;
+        let master = pty_pair.master;
+        // ...
+
+        let (output_sender, output_receiver) = mpsc::channel::<ConsoleLine>();
+        let (input_sender, input_receiver) = mpsc::channel::<String>();
+        let reader = master.try_clone_reader()?;
+        let mut writer = master.take_writer()?;
+
+        let error_sender = output_sender.clone();
+        let writer_thread = thread::spawn(move || {
+            for code in input_receiver {
+                if let Err(e) = writeln!(writer, "{}", code).and_then(|_| writer.flush()) {
+                    log::error!("Failed to send to SAPF: {}", e);
+                    let note = ConsoleLine::note(format!("failed to send to sapf: {}", e));
+                    if error_sender.send(note).is_err() {
+                        break;
+                    }
+                } else {
+                    log::info!("Sent: {}", code.trim());
+                }
+            }
+        });
+
+        let alive = Arc::new(AtomicBool::new(true));
+        let reader_alive = Arc::clone(&alive);
+        let reader_thread = thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut line = String::new();
+
+            loop {
+                line.clear();
+                match reader.read_line(&mut line) {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        let trimmed = strip_ansi(&line).trim_end().to_string();
+                        if !trimmed.is_empty() {
+                            log::debug!("{:?}", trimmed);
+                            if output_sender.send(ConsoleLine::output(trimmed)).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Error reading from PTY: {}", e);
+                        break;
+                    }
+                }
+            }
+            reader_alive.store(false, Ordering::Relaxed);
+        });
+
+        Ok(Self {
+            name: name.to_string(),
+            sender: input_sender,
+            receiver: output_receiver,
+            send_queue: VecDeque::new(),
+            send_queue_total: 0,
+            next_send_at: Instant::now() + STARTUP_DELAY,
+            child,
+            alive,
+            writer_thread,
+            reader_thread,
+        })
+    }
+
+    // Writes `commands` straight away, skipping the queue, then ends the
+    // process and both threads
+    pub fn shutdown(mut self, commands: &[String]) {
+        self.send_queue.clear();
+        for command in commands {
+            self.write(command);
+        }
+
+        // The writer thread finishes what it was given once the sender is gone
+        drop(self.sender);
+        let _ = self.writer_thread.join();
+        if !commands.is_empty() {
+            thread::sleep(EXIT_GRACE);
+        }
+
+        if let Err(e) = self.child.kill() {
+            log::debug!("sapf ({}) already gone: {}", self.name, e);
+        }
+        let _ = self.child.wait();
+
+        // The PTY read fails once the process is gone, but don't hang the exit on it
+        let deadline = Instant::now() + READER_EXIT_TIMEOUT;
+        while !self.reader_thread.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        if self.reader_thread.is_finished() {
+            let _ = self.reader_thread.join();
+        } else {
+            log::debug!("sapf ({}) reader still blocked, leaving it", self.name);
+        }
+    }
+
+    // Returns false when the queue is full and the line was dropped
+    pub fn enqueue(&mut self, line: String) -> bool {
+        if self.send_queue.is_empty() {
+            self.send_queue_total = 0;
+        }
+        if self.send_queue.len() >= MAX_SEND_QUEUE {
+            return false;
+        }
+
+        self.send_queue.push_back(line);
+        self.send_queue_total += 1;
+        true
+    }
+
+    // Writes due lines, returning how long to wait for the next one if any are left
+    pub fn process_queue(&mut self, delay: Duration) -> Option<Duration> {
+        while !self.send_queue.is_empty() {
+            let now = Instant::now();
+            if now < self.next_send_at {
+                return Some(self.next_send_at - now);
+            }
+
+            if let Some(line) = self.send_queue.pop_front() {
+                self.write(&line);
+            }
+            self.next_send_at = now + delay;
+        }
+
+        None
+    }
+
+    pub fn queue_progress(&self) -> Option<(usize, usize)> {
+        if self.send_queue.is_empty() {
+            return None;
+        }
+
+        let sent = self.send_queue_total - self.send_queue.len();
+        Some((sent, self.send_queue_total))
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    pub fn drain_output(&self) -> Vec<ConsoleLine> {
+        self.receiver.try_iter().collect()
+    }
+
+    fn write(&self, code: &str) {
+        log::debug!("Sending to SAPF ({}): {}", self.name, code);
+        if self.sender.send(code.to_string()).is_err() {
+            log::error!(
+                "Failed to send to SAPF ({}): writer thread is gone",
+                self.name
+            );
+        }
+    }
+}
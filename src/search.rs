@@ -0,0 +1,26 @@
+pub struct LineMatch {
+    pub line_number: usize,
+    pub char_offset: usize,
+    pub line: String,
+}
+
+pub fn find_in_content(content: &str, query: &str) -> Vec<LineMatch> {
+    let mut matches = Vec::new();
+    if query.is_empty() {
+        return matches;
+    }
+
+    let mut line_start = 0;
+    for (line_idx, line) in content.split('\n').enumerate() {
+        for (byte_idx, _) in line.match_indices(query) {
+            matches.push(LineMatch {
+                line_number: line_idx + 1,
+                char_offset: line_start + line[..byte_idx].chars().count(),
+                line: line.trim().to_string(),
+            });
+        }
+        line_start += line.chars().count() + 1;
+    }
+
+    matches
+}
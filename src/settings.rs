@@ -0,0 +1,338 @@
+use std::path::PathBuf;
+
+use eframe::egui::{self, Ui};
+use serde::{Deserialize, Serialize};
+
+use crate::{keymap::Keymap, log::LogLevel, sapf::PRIMARY_CONNECTION};
+
+const CONFIG_DIR: &str = "sapf-as-plain-text";
+pub const DEFAULT_CONSOLE_HEIGHT: f32 = 180.0;
+pub const MIN_FONT_SIZE: f32 = 8.0;
+pub const MAX_FONT_SIZE: f32 = 40.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsoleLayout {
+    #[default]
+    Bottom,
+    // Sent code and sapf output interleaved in a panel beside the editor
+    Transcript,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub transparent: bool,
+    pub opacity: f32,
+    pub always_on_top: bool,
+    pub restart_sapf_on_new_session: bool,
+    pub echo_sent: bool,
+    pub send_line_delay_ms: u64,
+    pub preserve_line_endings: bool,
+    pub live_debounce_ms: u64,
+    pub console_layout: ConsoleLayout,
+    // Program started for every connection, a name on PATH or a path, `~/` allowed
+    pub sapf_binary: String,
+    // Names of the sapf processes to run, the first one is the primary
+    pub connections: Vec<String>,
+    // Echo what would be sent to the console instead of sending it
+    pub dry_run: bool,
+    pub literal_completions: bool,
+    // Accepting a completion mid-word also replaces the part after the cursor
+    pub completion_replaces_word: bool,
+    // Words with a documented signature complete with their arguments as placeholders
+    pub snippet_completions: bool,
+    pub show_minimap: bool,
+    // Faint glyphs for spaces, tabs, stray carriage returns and line ends
+    pub show_whitespace: bool,
+    // Soft limit on open buffers, 0 for none
+    pub max_buffers: usize,
+    // What `Ctrl+L` sends, `{code}` is replaced with the current line
+    pub loop_template: String,
+    // Evaluated code goes through `audition_template` so new patches start quiet
+    pub safe_audition: bool,
+    pub audition_template: String,
+    pub trim_leading_whitespace: bool,
+    // chrono format string for `Ctrl+Alt+T` comments
+    pub timestamp_format: String,
+    // Set by dragging the console divider
+    pub console_height: f32,
+    // Hides the console for more editor room, output keeps collecting
+    pub console_collapsed: bool,
+    // Editor font size, also changed with Ctrl + mouse wheel
+    pub font_size: f32,
+    // Sent by Ctrl+. and before re-triggering evals
+    pub stop_command: String,
+    // Closing the app sends the stop command and then `exit_command` before ending sapf
+    pub stop_on_exit: bool,
+    pub exit_command: String,
+    // Ctrl+Enter replaces the playing sound instead of layering on top of it
+    pub stop_before_eval: bool,
+    pub log_level: LogLevel,
+    // Program the buffer is piped through by Ctrl+Alt+F, e.g. `sapf-fmt --stdin`
+    pub formatter_command: String,
+    // Identical consecutive sapf output shows once as `line (×N)`
+    pub collapse_repeated_output: bool,
+    // Older console lines are dropped past this many, 0 for no limit
+    pub max_console_lines: usize,
+    // Seconds between saves of anything not saved yet (cursor moves, window size), 0 for off
+    pub autosave_secs: u64,
+    // Code sent from the clipboard is also appended to a "clipboard" buffer
+    pub keep_clipboard_evals: bool,
+    // Where the open and export dialogs were last used, for buffers without a file
+    pub last_open_dir: Option<PathBuf>,
+    pub last_export_dir: Option<PathBuf>,
+    // Comment lines that open and close a named region, `; region: drums` ... `; end`
+    pub region_start: String,
+    pub region_end: String,
+    // Evaluated lines starting with this go to sapf as-is without it, empty for off
+    pub raw_command_prefix: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            transparent: true,
+            opacity: 1.0,
+            always_on_top: false,
+            restart_sapf_on_new_session: false,
+            echo_sent: false,
+            send_line_delay_ms: 10,
+            preserve_line_endings: true,
+            live_debounce_ms: 500,
+            console_layout: ConsoleLayout::Bottom,
+            sapf_binary: "sapf".to_string(),
+            connections: vec![PRIMARY_CONNECTION.to_string()],
+            dry_run: false,
+            literal_completions: true,
+            completion_replaces_word: true,
+            snippet_completions: true,
+            show_minimap: false,
+            show_whitespace: false,
+            max_buffers: 0,
+            loop_template: "{code} loop".to_string(),
+            safe_audition: false,
+            audition_template: "{code} .1 *".to_string(),
+            trim_leading_whitespace: false,
+            timestamp_format: "%Y-%m-%d %H:%M:%S".to_string(),
+            console_height: DEFAULT_CONSOLE_HEIGHT,
+            console_collapsed: false,
+            font_size: 14.0,
+            stop_command: "stop".to_string(),
+            stop_on_exit: true,
+            exit_command: "clear".to_string(),
+            stop_before_eval: false,
+            log_level: LogLevel::Info,
+            formatter_command: String::new(),
+            collapse_repeated_output: true,
+            max_console_lines: 10_000,
+            autosave_secs: 30,
+            keep_clipboard_evals: false,
+            last_open_dir: None,
+            last_export_dir: None,
+            region_start: "; region:".to_string(),
+            region_end: "; end".to_string(),
+            raw_command_prefix: String::new(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn window_opacity(&self) -> f32 {
+        if self.transparent {
+            self.opacity.clamp(0.2, 1.0)
+        } else {
+            1.0
+        }
+    }
+
+    pub fn window_level(&self) -> egui::WindowLevel {
+        if self.always_on_top {
+            egui::WindowLevel::AlwaysOnTop
+        } else {
+            egui::WindowLevel::Normal
+        }
+    }
+
+    pub fn wrap_in_loop(&self, code: &str) -> String {
+        self.loop_template.replace("{code}", code)
+    }
+
+    pub fn wrap_for_audition(&self, code: &str) -> String {
+        self.audition_template.replace("{code}", code)
+    }
+
+    pub fn sapf_program(&self) -> PathBuf {
+        let binary = self.sapf_binary.trim();
+        if binary.is_empty() {
+            return PathBuf::from("sapf");
+        }
+        match (binary.strip_prefix("~/"), dirs::home_dir()) {
+            (Some(rest), Some(home)) => home.join(rest),
+            _ => PathBuf::from(binary),
+        }
+    }
+
+    pub fn connection_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for name in &self.connections {
+            let name = name.trim();
+            if !name.is_empty() && !names.iter().any(|existing| existing == name) {
+                names.push(name.to_string());
+            }
+        }
+
+        if names.is_empty() {
+            names.push(PRIMARY_CONNECTION.to_string());
+        }
+        names
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, keymap: &Keymap) {
+        ui.checkbox(&mut self.transparent, "transparent background")
+            .on_hover_text("Some platforms only pick this up after a restart");
+        ui.add_enabled(
+            self.transparent,
+            egui::Slider::new(&mut self.opacity, 0.2..=1.0).text("opacity"),
+        );
+        ui.checkbox(&mut self.always_on_top, "always on top");
+        ui.horizontal(|ui| {
+            ui.label("sapf binary:");
+            ui.text_edit_singleline(&mut self.sapf_binary)
+                .on_hover_text("Used the next time sapf starts, reconnect or restart to apply");
+        });
+        ui.checkbox(
+            &mut self.restart_sapf_on_new_session,
+            "restart sapf on new session",
+        );
+        ui.checkbox(&mut self.echo_sent, "echo sent code in the console");
+        ui.checkbox(
+            &mut self.collapse_repeated_output,
+            "collapse repeated output lines",
+        );
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.max_console_lines).range(0..=1_000_000));
+            ui.label("max console lines (0 for no limit)");
+        });
+        ui.checkbox(&mut self.dry_run, "dry run (don't send anything)");
+        ui.checkbox(
+            &mut self.stop_before_eval,
+            format!("stop before eval ({})", keymap.label("eval")),
+        );
+        ui.checkbox(
+            &mut self.keep_clipboard_evals,
+            "keep code sent from the clipboard in a buffer",
+        );
+        ui.horizontal(|ui| {
+            ui.label("stop command:");
+            ui.text_edit_singleline(&mut self.stop_command);
+        });
+        ui.checkbox(&mut self.stop_on_exit, "stop sound when closing")
+            .on_hover_text("Sends the stop command and the exit command before sapf is ended");
+        ui.add_enabled_ui(self.stop_on_exit, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("exit command:");
+                ui.text_edit_singleline(&mut self.exit_command);
+            });
+        });
+        ui.checkbox(
+            &mut self.trim_leading_whitespace,
+            "trim indentation from evaluated code",
+        );
+        ui.add(
+            egui::Slider::new(&mut self.font_size, MIN_FONT_SIZE..=MAX_FONT_SIZE)
+                .text("editor font size"),
+        );
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.send_line_delay_ms).range(0..=1000));
+            ui.label("ms between sent lines");
+        });
+        ui.checkbox(
+            &mut self.preserve_line_endings,
+            "keep original line endings on export",
+        );
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.live_debounce_ms).range(100..=5000));
+            ui.label("ms live eval debounce");
+        });
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.autosave_secs).range(0..=3600));
+            ui.label("s between autosaves (0 for off)");
+        });
+        ui.checkbox(
+            &mut self.literal_completions,
+            "complete note names and sample rates",
+        );
+        ui.checkbox(
+            &mut self.completion_replaces_word,
+            "completions replace the whole word",
+        );
+        ui.checkbox(
+            &mut self.snippet_completions,
+            "complete words with argument placeholders (Tab to the next)",
+        );
+        ui.checkbox(&mut self.show_minimap, "show minimap");
+        ui.checkbox(&mut self.show_whitespace, "show whitespace");
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.max_buffers).range(0..=200));
+            ui.label("max open buffers (0 for no limit)");
+        });
+        ui.horizontal(|ui| {
+            ui.label("loop template:");
+            ui.text_edit_singleline(&mut self.loop_template);
+        });
+        ui.checkbox(
+            &mut self.safe_audition,
+            "safe audition (send evals through the template)",
+        );
+        ui.horizontal(|ui| {
+            ui.label("audition template:");
+            ui.text_edit_singleline(&mut self.audition_template);
+        });
+        ui.horizontal(|ui| {
+            ui.label("timestamp format:");
+            ui.text_edit_singleline(&mut self.timestamp_format);
+        });
+        ui.horizontal(|ui| {
+            ui.label("formatter command:");
+            ui.text_edit_singleline(&mut self.formatter_command);
+        });
+        ui.horizontal(|ui| {
+            ui.label("region markers:");
+            ui.add(egui::TextEdit::singleline(&mut self.region_start).desired_width(80.0));
+            ui.add(egui::TextEdit::singleline(&mut self.region_end).desired_width(80.0));
+        });
+        ui.horizontal(|ui| {
+            ui.label("raw command prefix:");
+            ui.add(egui::TextEdit::singleline(&mut self.raw_command_prefix).desired_width(40.0))
+                .on_hover_text("Evaluated lines starting with this are sent verbatim, without it");
+        });
+        ui.checkbox(
+            &mut self.console_collapsed,
+            format!("hide the console ({})", keymap.label("toggle_console")),
+        );
+        ui.horizontal(|ui| {
+            ui.label("console:");
+            ui.radio_value(&mut self.console_layout, ConsoleLayout::Bottom, "bottom");
+            ui.radio_value(
+                &mut self.console_layout,
+                ConsoleLayout::Transcript,
+                "transcript",
+            );
+        });
+        ui.horizontal(|ui| {
+            ui.label("log:");
+            for level in LogLevel::ALL {
+                ui.radio_value(&mut self.log_level, level, level.label());
+            }
+        });
+    }
+}
+
+pub fn get_config_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = dirs::config_dir()
+        .or_else(dirs::home_dir)
+        .ok_or("Could not find config or home directory")?;
+
+    path.push(CONFIG_DIR);
+    Ok(path)
+}
@@ -0,0 +1,438 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tokenizer::{TokenKind, tokenize};
+
+const BOM: char = '\u{feff}';
+// How much of a file is sniffed for binary content
+const BINARY_SNIFF_LEN: usize = 8192;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+pub enum DecodedText {
+    Utf8(String),
+    // Invalid sequences were replaced with U+FFFD
+    Lossy(String),
+    Binary,
+}
+
+pub fn decode_text(bytes: &[u8]) -> DecodedText {
+    let head = &bytes[..bytes.len().min(BINARY_SNIFF_LEN)];
+    if head.contains(&0) {
+        return DecodedText::Binary;
+    }
+
+    match std::str::from_utf8(bytes) {
+        Ok(text) => DecodedText::Utf8(text.to_string()),
+        Err(_) => DecodedText::Lossy(String::from_utf8_lossy(bytes).into_owned()),
+    }
+}
+
+// Strips a leading BOM and turns CRLF into LF, remembering which ending the file used
+pub fn normalize_loaded_text(raw: &str) -> (String, LineEnding) {
+    let text = raw.strip_prefix(BOM).unwrap_or(raw);
+
+    if text.contains("\r\n") {
+        (text.replace("\r\n", "\n"), LineEnding::CrLf)
+    } else {
+        (text.to_string(), LineEnding::Lf)
+    }
+}
+
+pub fn with_line_ending(text: &str, line_ending: LineEnding) -> String {
+    match line_ending {
+        LineEnding::Lf => text.to_string(),
+        LineEnding::CrLf => text.replace('\n', "\r\n"),
+    }
+}
+
+// Drops trailing `\r` and other control whitespace from every line before it goes to sapf
+pub fn clean_eval_code(code: &str) -> String {
+    code.lines()
+        .map(|line| line.trim_end_matches(|c: char| c.is_whitespace() || c.is_control()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+enum EscapeState {
+    Text,
+    // Right after ESC
+    Escape,
+    // `ESC [`, runs to a final byte in `@`..=`~`
+    Csi,
+    // `ESC ]`, runs to BEL or `ESC \`
+    Osc,
+    OscEscape,
+}
+
+// Drops ANSI escape sequences (colors, cursor moves, window titles) and the
+// other control characters a terminal would act on, tabs stay
+pub fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut state = EscapeState::Text;
+    for c in text.chars() {
+        state = match state {
+            EscapeState::Text if c == '\x1b' => EscapeState::Escape,
+            EscapeState::Text => {
+                if c == '\t' || !c.is_control() {
+                    stripped.push(c);
+                }
+                EscapeState::Text
+            }
+            EscapeState::Escape => match c {
+                '[' => EscapeState::Csi,
+                ']' => EscapeState::Osc,
+                // Intermediate bytes, as in `ESC ( B`
+                ' '..='/' => EscapeState::Escape,
+                _ => EscapeState::Text,
+            },
+            EscapeState::Csi => match c {
+                '@'..='~' => EscapeState::Text,
+                _ => EscapeState::Csi,
+            },
+            EscapeState::Osc => match c {
+                '\x07' => EscapeState::Text,
+                '\x1b' => EscapeState::OscEscape,
+                _ => EscapeState::Osc,
+            },
+            EscapeState::OscEscape => match c {
+                '\\' => EscapeState::Text,
+                _ => EscapeState::Osc,
+            },
+        };
+    }
+    stripped
+}
+
+pub fn char_to_byte(text: &str, char_idx: usize) -> usize {
+    text.char_indices()
+        .nth(char_idx)
+        .map_or(text.len(), |(byte_idx, _)| byte_idx)
+}
+
+#[derive(Clone, Debug)]
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(idx, _)| idx + 1));
+        Self { line_starts }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    pub fn line_of_byte(&self, byte_idx: usize) -> usize {
+        self.line_starts
+            .partition_point(|&start| start <= byte_idx)
+            .saturating_sub(1)
+    }
+
+    // Byte range of the line without its trailing newline
+    pub fn line_range(&self, text: &str, line: usize) -> Range<usize> {
+        let start = self.line_starts.get(line).copied().unwrap_or(text.len());
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .map_or(text.len(), |next_start| next_start - 1);
+        start.min(text.len())..end.min(text.len()).max(start.min(text.len()))
+    }
+}
+
+// Collapses runs of spaces between tokens and drops trailing whitespace, keeping
+// indentation, strings and comments exactly as they are
+pub fn normalize_whitespace(text: &str) -> String {
+    let tokens = tokenize(text);
+    let mut normalized = String::with_capacity(text.len());
+    let mut at_line_start = true;
+
+    for (idx, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Whitespace => {
+                let trailing = tokens
+                    .get(idx + 1)
+                    .is_none_or(|next| next.kind == TokenKind::Newline);
+                if trailing {
+                    continue;
+                }
+                if at_line_start {
+                    normalized.push_str(token.text);
+                } else {
+                    normalized.push(' ');
+                }
+            }
+            TokenKind::Newline => {
+                normalized.push('\n');
+                at_line_start = true;
+            }
+            _ => {
+                normalized.push_str(token.text);
+                at_line_start = false;
+            }
+        }
+    }
+
+    normalized
+}
+
+// Toggles comments on the lines the char range `start..end` touches, returning the
+// new content and where the cursor ends up, still on its line and code
+pub fn toggle_comments_in(
+    content: &str,
+    (start, end): (usize, usize),
+    cursor_pos: usize,
+) -> (String, usize) {
+    let line_index = LineIndex::new(content);
+    let first = line_index.line_of_byte(char_to_byte(content, start));
+    let mut last = line_index.line_of_byte(char_to_byte(content, end));
+    // A selection ending at the start of a line doesn't take that line in
+    if last > first && line_index.line_range(content, last).start == char_to_byte(content, end) {
+        last -= 1;
+    }
+
+    let lines: Vec<&str> = (first..=last)
+        .map(|line| &content[line_index.line_range(content, line)])
+        .collect();
+    let toggled = toggle_line_comments(&lines);
+
+    let cursor_byte = char_to_byte(content, cursor_pos);
+    let cursor_line = line_index.line_of_byte(cursor_byte).clamp(first, last);
+    let cursor_line_range = line_index.line_range(content, cursor_line);
+    let column = content[cursor_line_range.start..cursor_byte.max(cursor_line_range.start)]
+        .chars()
+        .count();
+    let old_line = lines[cursor_line - first];
+    let new_len = toggled[cursor_line - first].chars().count();
+    let indent = old_line.chars().count() - old_line.trim_start().chars().count();
+    // A cursor in the indentation stays put, one in the code moves with it
+    let column = if column <= indent {
+        column
+    } else {
+        column
+            .saturating_add_signed(new_len as isize - old_line.chars().count() as isize)
+            .clamp(indent, new_len)
+    };
+
+    let block_start = line_index.line_range(content, first).start;
+    let block_end = line_index.line_range(content, last).end;
+    let new_content = format!(
+        "{}{}{}",
+        &content[..block_start],
+        toggled.join("\n"),
+        &content[block_end..]
+    );
+    let cursor_line_start: usize = content[..block_start].chars().count()
+        + toggled[..cursor_line - first]
+            .iter()
+            .map(|line| line.chars().count() + 1)
+            .sum::<usize>();
+
+    (new_content, cursor_line_start + column)
+}
+
+// Comments the lines out after their indentation, or uncomments them when every
+// non-blank one already starts with `;`. Blank lines are left alone
+fn toggle_line_comments(lines: &[&str]) -> Vec<String> {
+    let is_commented = |line: &&str| line.trim_start().starts_with(';');
+    let uncomment = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .all(is_commented);
+
+    lines
+        .iter()
+        .map(|line| {
+            if line.trim().is_empty() {
+                return line.to_string();
+            }
+
+            let indent = line.len() - line.trim_start().len();
+            let (indentation, code) = line.split_at(indent);
+            if uncomment {
+                let code = code.strip_prefix(';').unwrap_or(code);
+                format!("{}{}", indentation, code.strip_prefix(' ').unwrap_or(code))
+            } else {
+                format!("{}; {}", indentation, code)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn char_to_byte_counts_multibyte_chars() {
+        let text = "é🎵a";
+        assert_eq!(char_to_byte(text, 0), 0);
+        assert_eq!(char_to_byte(text, 1), 2);
+        assert_eq!(char_to_byte(text, 2), 6);
+        assert_eq!(char_to_byte(text, 3), text.len());
+        // Past the end clamps to the end
+        assert_eq!(char_to_byte(text, 10), text.len());
+    }
+
+    #[test]
+    fn line_index_with_emoji_and_accents() {
+        let text = "café 🎵\nsinosc ñ\nend";
+        let index = LineIndex::new(text);
+        assert_eq!(index.line_count(), 3);
+
+        let cursor = char_to_byte(text, "café 🎵\nsi".chars().count());
+        let line = index.line_of_byte(cursor);
+        assert_eq!(line, 1);
+        assert_eq!(&text[index.line_range(text, line)], "sinosc ñ");
+        assert_eq!(&text[index.line_range(text, 0)], "café 🎵");
+    }
+
+    #[test]
+    fn last_line_without_trailing_newline() {
+        let text = "one\ntwo";
+        let index = LineIndex::new(text);
+        assert_eq!(index.line_count(), 2);
+        assert_eq!(index.line_of_byte(text.len()), 1);
+        assert_eq!(&text[index.line_range(text, 1)], "two");
+        // A line past the end is empty rather than out of bounds
+        assert_eq!(index.line_range(text, 5), text.len()..text.len());
+    }
+
+    #[test]
+    fn cursor_on_newline_belongs_to_its_line() {
+        let text = "ab\ncd\n";
+        let index = LineIndex::new(text);
+        // The `\n` ending "ab" is still on line 0, right after it is line 1
+        assert_eq!(index.line_of_byte(2), 0);
+        assert_eq!(index.line_of_byte(3), 1);
+        // The trailing newline starts an empty last line
+        assert_eq!(index.line_count(), 3);
+        assert_eq!(index.line_of_byte(text.len()), 2);
+        assert_eq!(&text[index.line_range(text, 2)], "");
+    }
+
+    #[test]
+    fn normalize_collapses_spaces_between_tokens() {
+        assert_eq!(
+            normalize_whitespace("440   0  sinosc\t.3 *"),
+            "440 0 sinosc .3 *"
+        );
+    }
+
+    #[test]
+    fn normalize_leaves_strings_and_comments_alone() {
+        let text = "\"a   b\"   play ;  two  spaces\n";
+        assert_eq!(
+            normalize_whitespace(text),
+            "\"a   b\" play ;  two  spaces\n"
+        );
+
+        let text = "\"say \\\"  hi  \\\"\"   play";
+        assert_eq!(normalize_whitespace(text), "\"say \\\"  hi  \\\"\" play");
+
+        // An unterminated string keeps everything after its quote
+        let text = "1   \"no   end  \n  2  ";
+        assert_eq!(normalize_whitespace(text), "1 \"no   end  \n  2  ");
+    }
+
+    #[test]
+    fn normalize_keeps_indentation_and_drops_trailing_whitespace() {
+        let text = "[\n    1   2  \n\t3 \t\n]   \n";
+        assert_eq!(normalize_whitespace(text), "[\n    1 2\n\t3\n]\n");
+    }
+
+    #[test]
+    fn toggle_comments_on_a_mixed_selection_comments_everything() {
+        let content = "; sinosc\nplay\n\nstop";
+        let (toggled, _) = toggle_comments_in(content, (0, content.chars().count()), 0);
+        // Only some lines were commented, so all of them get commented, blank lines stay
+        assert_eq!(toggled, "; ; sinosc\n; play\n\n; stop");
+
+        let (restored, _) = toggle_comments_in(&toggled, (0, toggled.chars().count()), 0);
+        assert_eq!(restored, content);
+    }
+
+    #[test]
+    fn toggle_comments_keeps_indentation() {
+        let content = "  sinosc\n\t.5 *";
+        let (toggled, _) = toggle_comments_in(content, (0, content.chars().count()), 0);
+        assert_eq!(toggled, "  ; sinosc\n\t; .5 *");
+
+        let (restored, _) = toggle_comments_in(&toggled, (0, toggled.chars().count()), 0);
+        assert_eq!(restored, content);
+    }
+
+    #[test]
+    fn toggle_comments_on_a_partial_selection_takes_whole_lines() {
+        let content = "one\ntwo\nthree\nfour";
+        // From the middle of "two" to the middle of "three"
+        let (toggled, _) = toggle_comments_in(content, (5, 10), 10);
+        assert_eq!(toggled, "one\n; two\n; three\nfour");
+
+        // A selection ending at the start of "three" leaves that line alone
+        let (toggled, _) = toggle_comments_in(content, (4, 8), 8);
+        assert_eq!(toggled, "one\n; two\nthree\nfour");
+    }
+
+    #[test]
+    fn toggle_comments_moves_the_cursor_with_the_code() {
+        let content = "one\n  two";
+        // Cursor between "t" and "wo"
+        let (toggled, cursor_pos) = toggle_comments_in(content, (7, 7), 7);
+        assert_eq!(toggled, "one\n  ; two");
+        assert_eq!(cursor_pos, 9);
+        assert_eq!(&toggled[cursor_pos..], "wo");
+
+        let (restored, cursor_pos) = toggle_comments_in(&toggled, (9, 9), 9);
+        assert_eq!(restored, content);
+        assert_eq!(cursor_pos, 7);
+
+        // A cursor in the indentation stays where it was
+        let (_, cursor_pos) = toggle_comments_in(content, (4, 4), 4);
+        assert_eq!(cursor_pos, 4);
+        let (_, cursor_pos) = toggle_comments_in(content, (6, 6), 6);
+        assert_eq!(cursor_pos, 6);
+    }
+
+    #[test]
+    fn clean_eval_code_drops_trailing_carriage_returns() {
+        assert_eq!(clean_eval_code("foo\r"), "foo");
+        assert_eq!(clean_eval_code("sinosc\r\nplay \r"), "sinosc\nplay");
+    }
+
+    #[test]
+    fn crlf_file_loads_without_carriage_returns() {
+        let (content, line_ending) = normalize_loaded_text("sinosc\r\n.5 *\r\nplay\r\n");
+        assert_eq!(content, "sinosc\n.5 *\nplay\n");
+        assert!(!content.contains('\r'));
+        assert_eq!(line_ending, LineEnding::CrLf);
+    }
+
+    #[test]
+    fn bom_file_loads_without_the_bom() {
+        let (content, line_ending) = normalize_loaded_text("\u{feff}sinosc\nplay");
+        assert_eq!(content, "sinosc\nplay");
+        assert_eq!(line_ending, LineEnding::Lf);
+
+        let (content, line_ending) = normalize_loaded_text("\u{feff}sinosc\r\nplay");
+        assert_eq!(content, "sinosc\nplay");
+        assert_eq!(line_ending, LineEnding::CrLf);
+    }
+
+    #[test]
+    fn preserved_line_endings_round_trip() {
+        for raw in ["a\r\nb\r\n\r\nc", "a\nb\n\nc\n", ""] {
+            let (content, line_ending) = normalize_loaded_text(raw);
+            assert_eq!(with_line_ending(&content, line_ending), raw);
+        }
+    }
+}
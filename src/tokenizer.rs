@@ -0,0 +1,252 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Whitespace,
+    Newline,
+    Comment,
+    String,
+    Open,
+    Close,
+    Word,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    // Byte offset into the tokenized text
+    pub start: usize,
+}
+
+// Splits sapf code into tokens without losing anything, concatenating the
+// token texts gives back the input. Unterminated strings run to the end.
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            '\n' => TokenKind::Newline,
+            '(' | '[' | '{' => TokenKind::Open,
+            ')' | ']' | '}' => TokenKind::Close,
+            ';' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                TokenKind::Comment
+            }
+            '"' => {
+                let mut escaped = false;
+                for (_, c) in chars.by_ref() {
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+                TokenKind::String
+            }
+            c if c.is_whitespace() => {
+                while chars
+                    .next_if(|&(_, c)| c.is_whitespace() && c != '\n')
+                    .is_some()
+                {}
+                TokenKind::Whitespace
+            }
+            _ => {
+                while chars.next_if(|&(_, c)| !is_delimiter(c)).is_some() {}
+                TokenKind::Word
+            }
+        };
+
+        let end = chars.peek().map_or(text.len(), |&(idx, _)| idx);
+        tokens.push(Token {
+            kind,
+            text: &text[start..end],
+            start,
+        });
+    }
+
+    tokens
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '[' | ']' | '{' | '}' | ';' | '"')
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BracketError {
+    // Byte offset of the offending bracket
+    pub position: usize,
+    pub bracket: char,
+    pub unclosed: bool,
+}
+
+impl std::fmt::Display for BracketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.unclosed {
+            write!(f, "unclosed {}", self.bracket)
+        } else {
+            write!(f, "unexpected {}", self.bracket)
+        }
+    }
+}
+
+// Byte range of the word token touching `byte_idx`, so a cursor right after a word still finds it
+pub fn word_at(text: &str, byte_idx: usize) -> Option<std::ops::Range<usize>> {
+    tokenize(text)
+        .into_iter()
+        .filter(|token| token.kind == TokenKind::Word)
+        .map(|token| token.start..token.start + token.text.len())
+        .find(|range| range.start <= byte_idx && byte_idx <= range.end)
+}
+
+// Brackets inside strings and comments don't count
+pub fn check_brackets(text: &str) -> Result<(), BracketError> {
+    let mut stack: Vec<(usize, char)> = Vec::new();
+
+    for token in tokenize(text) {
+        let Some(bracket) = token.text.chars().next() else {
+            continue;
+        };
+        match token.kind {
+            TokenKind::Open => stack.push((token.start, bracket)),
+            TokenKind::Close => match stack.pop() {
+                Some((_, open)) if closing_bracket(open) == bracket => {}
+                _ => {
+                    return Err(BracketError {
+                        position: token.start,
+                        bracket,
+                        unclosed: false,
+                    });
+                }
+            },
+            _ => {}
+        }
+    }
+
+    match stack.pop() {
+        Some((position, bracket)) => Err(BracketError {
+            position,
+            bracket,
+            unclosed: true,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn closing_bracket(open: char) -> char {
+    match open {
+        '(' => ')',
+        '[' => ']',
+        _ => '}',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(text: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(text)
+            .into_iter()
+            .map(|token| (token.kind, token.text))
+            .collect()
+    }
+
+    #[test]
+    fn tokens_concatenate_back_to_the_input() {
+        let text = "  440 [1 2]  sinosc \"a  b\" ; note  \n\t{x}\"open";
+        let tokens = tokenize(text);
+        assert_eq!(
+            tokens.iter().map(|token| token.text).collect::<String>(),
+            text
+        );
+        for token in &tokens {
+            assert_eq!(
+                &text[token.start..token.start + token.text.len()],
+                token.text
+            );
+        }
+    }
+
+    #[test]
+    fn escaped_quotes_stay_inside_the_string() {
+        assert_eq!(
+            kinds(r#""say \"hi\"" play"#),
+            vec![
+                (TokenKind::String, r#""say \"hi\"""#),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Word, "play"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_string_runs_to_the_end() {
+        assert_eq!(
+            kinds("1 \"no end\n2"),
+            vec![
+                (TokenKind::Word, "1"),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::String, "\"no end\n2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn balanced_brackets_pass() {
+        assert_eq!(check_brackets("[1 (2 {3})] play"), Ok(()));
+        assert_eq!(check_brackets(""), Ok(()));
+    }
+
+    #[test]
+    fn mismatched_close_reports_the_close() {
+        assert_eq!(
+            check_brackets("1 (]"),
+            Err(BracketError {
+                position: 3,
+                bracket: ']',
+                unclosed: false,
+            })
+        );
+        // A close with nothing open
+        assert_eq!(
+            check_brackets("é)"),
+            Err(BracketError {
+                position: 2,
+                bracket: ')',
+                unclosed: false,
+            })
+        );
+    }
+
+    #[test]
+    fn unclosed_open_reports_the_innermost() {
+        assert_eq!(
+            check_brackets("[1 (2 {3}"),
+            Err(BracketError {
+                position: 3,
+                bracket: '(',
+                unclosed: true,
+            })
+        );
+    }
+
+    #[test]
+    fn brackets_in_strings_and_comments_are_ignored() {
+        assert_eq!(check_brackets("\"(]\" [1] ; ) [ {\n(2)"), Ok(()));
+        assert_eq!(check_brackets("\"unterminated ("), Ok(()));
+    }
+
+    #[test]
+    fn comments_run_to_the_end_of_the_line() {
+        assert_eq!(
+            kinds("play ; \"not a string\"\nstop"),
+            vec![
+                (TokenKind::Word, "play"),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Comment, "; \"not a string\""),
+                (TokenKind::Newline, "\n"),
+                (TokenKind::Word, "stop"),
+            ]
+        );
+    }
+}
@@ -1,31 +1,157 @@
-use eframe::egui::{self, Color32, Stroke, Style, Theme, style::Selection};
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use eframe::egui::{self, Color32, Stroke, Style, Theme, Visuals, style::Selection};
+use serde::Deserialize;
+
+use crate::settings::get_config_dir;
 
 const MAIN_COLOR_LIGHT: (u8, u8, u8) = (159, 185, 194);
 const MAIN_COLOR_DARK: (u8, u8, u8) = (133, 152, 158);
+const THEME_FILE: &str = "theme.json";
 
-pub fn setup_custom_style(ctx: &egui::Context) {
-    if ctx.style().visuals.dark_mode {
-        ctx.style_mut_of(Theme::Dark, custom_colors)
-    } else {
-        ctx.style_mut_of(Theme::Light, custom_colors)
+// Colors for one of the light and dark modes, `None` keeps egui's own
+#[derive(Clone, Debug)]
+pub struct ThemeColors {
+    pub selection: Color32,
+    pub hover: Color32,
+    pub background: Option<Color32>,
+    pub text: Option<Color32>,
+    pub error: Option<Color32>,
+    // Console notes
+    pub info: Option<Color32>,
+}
+
+impl ThemeColors {
+    fn with_main_color((r, g, b): (u8, u8, u8)) -> Self {
+        Self {
+            selection: Color32::from_rgb(r, g, b),
+            hover: Color32::from_rgb(r, g, b),
+            background: None,
+            text: None,
+            error: None,
+            info: None,
+        }
     }
 }
 
-fn custom_colors(style: &mut Style) {
-    if !style.visuals.dark_mode {
-        style.visuals.selection = Selection {
-            bg_fill: Color32::from_rgb(MAIN_COLOR_LIGHT.0, MAIN_COLOR_LIGHT.1, MAIN_COLOR_LIGHT.2),
-            stroke: Stroke::new(2.0, Color32::BLACK),
+#[derive(Clone, Debug)]
+pub struct ThemePalette {
+    pub light: ThemeColors,
+    pub dark: ThemeColors,
+}
+
+impl Default for ThemePalette {
+    fn default() -> Self {
+        Self {
+            light: ThemeColors::with_main_color(MAIN_COLOR_LIGHT),
+            dark: ThemeColors::with_main_color(MAIN_COLOR_DARK),
+        }
+    }
+}
+
+impl ThemePalette {
+    pub fn colors(&self, dark_mode: bool) -> &ThemeColors {
+        if dark_mode { &self.dark } else { &self.light }
+    }
+}
+
+// `{ "dark": { "selection": "#85989e", ... }, "light": { ... } }`
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ThemeFile {
+    light: HashMap<String, String>,
+    dark: HashMap<String, String>,
+}
+
+pub fn get_theme_file_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = get_config_dir()?;
+    path.push(THEME_FILE);
+    Ok(path)
+}
+
+// The built-in palette with whatever the user's theme file overrides, plus a
+// warning for every value that couldn't be used
+pub fn load_theme() -> (ThemePalette, Vec<String>) {
+    let mut palette = ThemePalette::default();
+    let path = match get_theme_file_path() {
+        Ok(path) if path.exists() => path,
+        _ => return (palette, Vec::new()),
+    };
+
+    let file: ThemeFile = match fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+    {
+        Ok(file) => file,
+        Err(e) => return (palette, vec![format!("{}: {}", THEME_FILE, e)]),
+    };
+
+    let mut warnings = Vec::new();
+    apply_theme_values(&mut palette.light, &file.light, "light", &mut warnings);
+    apply_theme_values(&mut palette.dark, &file.dark, "dark", &mut warnings);
+    (palette, warnings)
+}
+
+fn apply_theme_values(
+    colors: &mut ThemeColors,
+    values: &HashMap<String, String>,
+    mode: &str,
+    warnings: &mut Vec<String>,
+) {
+    for (key, value) in values {
+        let Ok(color) = Color32::from_hex(value.trim()) else {
+            warnings.push(format!(
+                "{}: {}.{} \"{}\" isn't a #rrggbb color",
+                THEME_FILE, mode, key, value
+            ));
+            continue;
         };
-        style.visuals.widgets.hovered.weak_bg_fill =
-            Color32::from_rgb(MAIN_COLOR_LIGHT.0, MAIN_COLOR_LIGHT.1, MAIN_COLOR_LIGHT.2);
+
+        match key.as_str() {
+            "selection" => colors.selection = color,
+            "hover" => colors.hover = color,
+            "background" => colors.background = Some(color),
+            "text" => colors.text = Some(color),
+            "error" => colors.error = Some(color),
+            "info" => colors.info = Some(color),
+            _ => warnings.push(format!(
+                "{}: unknown color \"{}.{}\"",
+                THEME_FILE, mode, key
+            )),
+        }
+    }
+}
+
+pub fn setup_custom_style(ctx: &egui::Context, palette: &ThemePalette) {
+    ctx.style_mut_of(Theme::Dark, |style| custom_colors(style, &palette.dark));
+    ctx.style_mut_of(Theme::Light, |style| custom_colors(style, &palette.light));
+}
+
+fn custom_colors(style: &mut Style, colors: &ThemeColors) {
+    // Start over so a color removed from the theme file goes back to egui's
+    style.visuals = if style.visuals.dark_mode {
+        Visuals::dark()
     } else {
-        style.visuals.selection = Selection {
-            bg_fill: Color32::from_rgb(MAIN_COLOR_DARK.0, MAIN_COLOR_DARK.1, MAIN_COLOR_DARK.2),
-            stroke: Stroke::new(2.0, Color32::BLACK),
-        };
-        style.visuals.widgets.hovered.weak_bg_fill =
-            Color32::from_rgb(MAIN_COLOR_DARK.0, MAIN_COLOR_DARK.1, MAIN_COLOR_DARK.2);
+        Visuals::light()
+    };
+
+    style.visuals.selection = Selection {
+        bg_fill: colors.selection,
+        stroke: Stroke::new(2.0, Color32::BLACK),
+    };
+    style.visuals.widgets.hovered.weak_bg_fill = colors.hover;
+    if style.visuals.dark_mode {
         style.visuals.widgets.inactive.weak_bg_fill = style.visuals.faint_bg_color;
     }
+
+    if let Some(background) = colors.background {
+        style.visuals.panel_fill = background;
+        style.visuals.window_fill = background;
+    }
+    if colors.text.is_some() {
+        style.visuals.override_text_color = colors.text;
+    }
+    if let Some(error) = colors.error {
+        style.visuals.error_fg_color = error;
+    }
 }
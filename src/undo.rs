@@ -0,0 +1,282 @@
+use std::time::{Duration, Instant};
+
+const MAX_UNDO_STEPS: usize = 200;
+// Edits closer together than this undo as one step
+const COALESCE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub content: String,
+    pub cursor_pos: usize,
+}
+
+// `removed` was replaced by `inserted` at byte `start`
+#[derive(Clone, Debug)]
+struct Edit {
+    start: usize,
+    removed: String,
+    inserted: String,
+}
+
+impl Edit {
+    // The changed middle of `old` -> `new`, whatever both start and end with is left out
+    fn between(old: &str, new: &str) -> Self {
+        let mut prefix = old
+            .bytes()
+            .zip(new.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !old.is_char_boundary(prefix) {
+            prefix -= 1;
+        }
+
+        let max_suffix = old.len().min(new.len()) - prefix;
+        let mut suffix = old
+            .bytes()
+            .rev()
+            .zip(new.bytes().rev())
+            .take(max_suffix)
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !old.is_char_boundary(old.len() - suffix) {
+            suffix -= 1;
+        }
+
+        Self {
+            start: prefix,
+            removed: old[prefix..old.len() - suffix].to_string(),
+            inserted: new[prefix..new.len() - suffix].to_string(),
+        }
+    }
+
+    fn apply(&self, content: &mut String) {
+        content.replace_range(self.start..self.start + self.removed.len(), &self.inserted);
+    }
+
+    fn revert(&self, content: &mut String) {
+        content.replace_range(self.start..self.start + self.inserted.len(), &self.removed);
+    }
+}
+
+// The edits one undo takes back, with the cursor on either side of them
+#[derive(Clone, Debug)]
+struct Step {
+    edits: Vec<Edit>,
+    cursor_before: usize,
+    cursor_after: usize,
+}
+
+// The edits made to one buffer, kept by the app so they outlive the editor
+// widget and buffer switches. Only the latest content is held in full
+#[derive(Clone, Debug, Default)]
+pub struct UndoHistory {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+    // The content as of the last recorded edit, what the steps are applied to
+    current: Option<Snapshot>,
+    last_edit: Option<Instant>,
+}
+
+impl UndoHistory {
+    // Takes note of the content before the first edit, later calls do nothing
+    pub fn seed(&mut self, content: &str, cursor_pos: usize) {
+        if self.current.is_none() {
+            self.current = Some(Snapshot {
+                content: content.to_string(),
+                cursor_pos,
+            });
+        }
+    }
+
+    // Called after every change, the first one only takes note of the content
+    pub fn record(&mut self, content: &str, cursor_pos: usize) {
+        let now = Instant::now();
+        let Some(current) = &mut self.current else {
+            self.seed(content, cursor_pos);
+            self.last_edit = Some(now);
+            return;
+        };
+        if current.content == content {
+            current.cursor_pos = cursor_pos;
+            return;
+        }
+
+        let edit = Edit::between(&current.content, content);
+        edit.apply(&mut current.content);
+        let cursor_before = std::mem::replace(&mut current.cursor_pos, cursor_pos);
+
+        let coalesce = self
+            .last_edit
+            .is_some_and(|last| now.duration_since(last) < COALESCE_INTERVAL);
+        match self.undo.last_mut() {
+            Some(step) if coalesce => {
+                step.edits.push(edit);
+                step.cursor_after = cursor_pos;
+            }
+            _ => {
+                self.undo.push(Step {
+                    edits: vec![edit],
+                    cursor_before,
+                    cursor_after: cursor_pos,
+                });
+                if self.undo.len() > MAX_UNDO_STEPS {
+                    self.undo.remove(0);
+                }
+            }
+        }
+        self.redo.clear();
+        self.last_edit = Some(now);
+    }
+
+    // The next edit starts a step of its own, for changes made outside the editor
+    pub fn break_step(&mut self) {
+        self.last_edit = None;
+    }
+
+    pub fn undo(&mut self, content: &str, cursor_pos: usize) -> Option<Snapshot> {
+        self.catch_up(content, cursor_pos);
+        let step = self.undo.pop()?;
+        let current = self.current.as_mut()?;
+        for edit in step.edits.iter().rev() {
+            edit.revert(&mut current.content);
+        }
+        current.cursor_pos = step.cursor_before;
+        self.redo.push(step);
+        self.step_taken()
+    }
+
+    pub fn redo(&mut self, content: &str, cursor_pos: usize) -> Option<Snapshot> {
+        self.catch_up(content, cursor_pos);
+        let step = self.redo.pop()?;
+        let current = self.current.as_mut()?;
+        for edit in &step.edits {
+            edit.apply(&mut current.content);
+        }
+        current.cursor_pos = step.cursor_after;
+        self.undo.push(step);
+        self.step_taken()
+    }
+
+    // Records whatever changed since the last edit as a step of its own, so
+    // undoing starts from the content actually in the buffer
+    fn catch_up(&mut self, content: &str, cursor_pos: usize) {
+        self.break_step();
+        self.record(content, cursor_pos);
+    }
+
+    fn step_taken(&mut self) -> Option<Snapshot> {
+        self.last_edit = None;
+        self.current.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(history: &mut UndoHistory, content: &mut String, at: usize, text: &str) {
+        content.insert_str(at, text);
+        history.record(content, at + text.chars().count());
+    }
+
+    #[test]
+    fn undo_and_redo_walk_the_steps() {
+        let mut history = UndoHistory::default();
+        let mut content = "sinosc".to_string();
+        history.seed(&content, 0);
+
+        edit(&mut history, &mut content, 6, " play");
+        history.break_step();
+        edit(&mut history, &mut content, 0, "440 ");
+
+        let snapshot = history.undo(&content, 4).unwrap();
+        assert_eq!(snapshot.content, "sinosc play");
+        assert_eq!(snapshot.cursor_pos, 11);
+        let snapshot = history.undo(&snapshot.content, 11).unwrap();
+        assert_eq!(snapshot.content, "sinosc");
+        assert_eq!(snapshot.cursor_pos, 0);
+        assert!(history.undo(&snapshot.content, 0).is_none());
+
+        let snapshot = history.redo(&snapshot.content, 0).unwrap();
+        assert_eq!(snapshot.content, "sinosc play");
+        let snapshot = history.redo(&snapshot.content, 11).unwrap();
+        assert_eq!(snapshot.content, "440 sinosc play");
+        assert!(history.redo(&snapshot.content, 4).is_none());
+    }
+
+    #[test]
+    fn quick_edits_undo_as_one_step() {
+        let mut history = UndoHistory::default();
+        let mut content = String::new();
+        history.seed(&content, 0);
+        for (i, c) in ["s", "i", "n"].into_iter().enumerate() {
+            edit(&mut history, &mut content, i, c);
+        }
+
+        let snapshot = history.undo(&content, 3).unwrap();
+        assert_eq!(snapshot.content, "");
+        assert!(history.undo(&snapshot.content, 0).is_none());
+    }
+
+    #[test]
+    fn edits_between_multibyte_chars() {
+        let mut history = UndoHistory::default();
+        let mut content = "é🎵é".to_string();
+        history.seed(&content, 0);
+        // Replaces one multi-byte char with another that shares its leading byte
+        content = "é🎶é".to_string();
+        history.record(&content, 2);
+
+        let snapshot = history.undo(&content, 2).unwrap();
+        assert_eq!(snapshot.content, "é🎵é");
+        let snapshot = history.redo(&snapshot.content, 2).unwrap();
+        assert_eq!(snapshot.content, "é🎶é");
+
+        // `é` and `©` end in the same continuation byte
+        let mut history = UndoHistory::default();
+        history.seed("aéb", 0);
+        history.record("a©b", 2);
+        assert_eq!(history.undo("a©b", 2).unwrap().content, "aéb");
+    }
+
+    #[test]
+    fn a_new_edit_clears_redo() {
+        let mut history = UndoHistory::default();
+        let mut content = "a".to_string();
+        history.seed(&content, 0);
+        edit(&mut history, &mut content, 1, "b");
+
+        let snapshot = history.undo(&content, 2).unwrap();
+        content = snapshot.content;
+        edit(&mut history, &mut content, 1, "c");
+        assert!(history.redo(&content, 2).is_none());
+        assert_eq!(content, "ac");
+    }
+
+    #[test]
+    fn large_buffer_keeps_only_the_changes() {
+        let line = "440 0 sinosc .3 * play\n";
+        let mut content = line.repeat(5_000);
+        let mut history = UndoHistory::default();
+        history.seed(&content, 0);
+
+        for i in 0..MAX_UNDO_STEPS {
+            history.break_step();
+            edit(&mut history, &mut content, i * line.len(), ";");
+        }
+
+        let stored: usize = history
+            .undo
+            .iter()
+            .flat_map(|step| &step.edits)
+            .map(|edit| edit.removed.len() + edit.inserted.len())
+            .sum();
+        assert_eq!(stored, MAX_UNDO_STEPS);
+
+        let mut snapshot = history.undo(&content, 0).unwrap();
+        while let Some(previous) = history.undo(&snapshot.content, snapshot.cursor_pos) {
+            snapshot = previous;
+        }
+        assert_eq!(snapshot.content, line.repeat(5_000));
+    }
+}
@@ -1,17 +1,74 @@
-use eframe::egui::{self, ViewportCommand};
-use egui::{Align2, FontId, Id, PointerButton, Sense, UiBuilder};
+use eframe::egui::{self, ResizeDirection, ViewportCommand};
+use egui::{
+    Align2, CursorIcon, FontId, Id, PointerButton, Pos2, Rect, Sense, UiBuilder, Vec2, vec2,
+};
+use serde::{Deserialize, Serialize};
 
 use crate::WINDOW_TITLE;
 
+const RESIZE_GRIP_SIZE: f32 = 16.0;
+
+// Outer position and inner size of the window, in points
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WindowGeometry {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl WindowGeometry {
+    pub fn position(&self) -> Pos2 {
+        Pos2::new(self.x, self.y)
+    }
+
+    pub fn size(&self) -> Vec2 {
+        vec2(self.width, self.height)
+    }
+
+    // Shrunk to fit the monitor and moved back onto it. egui only knows the
+    // current monitor's size, so it's taken to start at the origin
+    pub fn clamped_to(&self, monitor: Vec2) -> Self {
+        let width = self.width.min(monitor.x);
+        let height = self.height.min(monitor.y);
+        Self {
+            x: self.x.clamp(0.0, monitor.x - width),
+            y: self.y.clamp(0.0, monitor.y - height),
+            width,
+            height,
+        }
+    }
+}
+
+// `None` until the platform has reported where the window is, and while it's
+// maximized or fullscreen so those don't replace the size to go back to
+pub fn current_geometry(ctx: &egui::Context) -> Option<WindowGeometry> {
+    ctx.input(|i| {
+        let viewport = i.viewport();
+        if viewport.maximized.unwrap_or(false) || viewport.fullscreen.unwrap_or(false) {
+            return None;
+        }
+        let outer = viewport.outer_rect?;
+        let inner = viewport.inner_rect?;
+        Some(WindowGeometry {
+            x: outer.min.x,
+            y: outer.min.y,
+            width: inner.width(),
+            height: inner.height(),
+        })
+    })
+}
+
 pub fn custom_window_frame(
     ctx: &egui::Context,
     title: &str,
+    opacity: f32,
     add_contents: impl FnOnce(&mut egui::Ui),
 ) {
     use egui::{CentralPanel, UiBuilder};
 
     let panel_frame = egui::Frame::new()
-        .fill(ctx.style().visuals.window_fill())
+        .fill(ctx.style().visuals.window_fill().gamma_multiply(opacity))
         .corner_radius(20)
         .stroke(ctx.style().visuals.widgets.noninteractive.fg_stroke)
         .outer_margin(1);
@@ -35,9 +92,25 @@ pub fn custom_window_frame(
         .shrink(4.0);
         let mut content_ui = ui.new_child(UiBuilder::new().max_rect(content_rect));
         add_contents(&mut content_ui);
+
+        resize_grip_ui(ui, app_rect);
     });
 }
 
+// Without decorations the window has no edges to drag, so the bottom right
+// corner starts a resize
+fn resize_grip_ui(ui: &mut egui::Ui, app_rect: Rect) {
+    let grip_rect = Rect::from_min_max(app_rect.max - Vec2::splat(RESIZE_GRIP_SIZE), app_rect.max);
+    let response = ui.interact(grip_rect, Id::new("resize_grip"), Sense::drag());
+    if response.hovered() || response.dragged() {
+        ui.ctx().set_cursor_icon(CursorIcon::ResizeNwSe);
+    }
+    if response.drag_started_by(PointerButton::Primary) {
+        ui.ctx()
+            .send_viewport_cmd(ViewportCommand::BeginResize(ResizeDirection::SouthEast));
+    }
+}
+
 fn title_bar_ui(ui: &mut egui::Ui, title_bar_rect: eframe::epaint::Rect, _title: &str) {
     let painter = ui.painter();
 